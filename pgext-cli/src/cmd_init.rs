@@ -2,7 +2,7 @@ use std::path::PathBuf;
 
 use anyhow::Result;
 
-use crate::config::WorkspaceConfig;
+use crate::config::{detect_pg_version, WorkspaceConfig};
 use crate::CmdInit;
 
 /// Initialze the workspace
@@ -17,10 +17,16 @@ pub fn cmd_init(cmd: CmdInit) -> Result<()> {
   if !PathBuf::from(cmd.pg_config.clone()).exists() {
     anyhow::bail!("pg_config does not exist");
   }
+  let pg_version = detect_pg_version(&cmd.pg_config)?;
+  println!("pg_version: {}", pg_version);
+  if !(13..=15).contains(&pg_version) {
+    anyhow::bail!("Postgres {} is not supported, use 13, 14 or 15", pg_version);
+  }
   let config = WorkspaceConfig {
     pg_config: cmd.pg_config,
     pg_data: cmd.pg_data,
     pg_contrib: cmd.pg_contrib,
+    pg_version,
   };
   // saving config
   std::fs::write(
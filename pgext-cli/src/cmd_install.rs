@@ -137,7 +137,8 @@ pub fn cmd_install_hook() -> Result<()> {
     .dir("pgextmgr")
     .run()?;
 
-  cmd!("cargo", "pgrx", "start", "pg15").dir("pgx_show_hooks").run()?;
+  let pg = format!("pg{}", config.pg_version);
+  cmd!("cargo", "pgrx", "start", &pg).dir("pgx_show_hooks").run()?;
 
   println!(
     "{} {}",
@@ -155,7 +156,7 @@ pub fn cmd_install_hook() -> Result<()> {
   println!("{}: setting your user as a superuser", style("Configure").blue().bold(),);
   client.execute(&format!("ALTER USER {} WITH SUPERUSER;", whoami), &[])?;
 
-  cmd!("cargo", "pgrx", "stop", "pg15").dir("pgx_show_hooks").run()?;
+  cmd!("cargo", "pgrx", "stop", &pg).dir("pgx_show_hooks").run()?;
 
   println!(
     "{} {}",
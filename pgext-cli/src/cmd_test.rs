@@ -13,7 +13,7 @@ use crate::cmd_install::create_workdir;
 use crate::config::{edit_pgconf, load_workspace_config};
 use crate::plugin::{find_plugin, load_plugin_db, CheckStrategy, InstallStrategy};
 use crate::resolve_pgxs::pgxs_installcheck;
-use crate::test_control::{pgx_start_pg15, pgx_stop_pg15, ExtTestControl};
+use crate::test_control::{pgx_start, pgx_stop, ExtTestControl};
 use crate::{CmdDemo, CmdTest, CmdTestAll, CmdTestSingle};
 
 /// Run the `demo` subcommand
@@ -229,10 +229,10 @@ pub fn cmd_test(cmd: CmdTest, pbar: Option<ProgressBar>) -> Result<Vec<String>>
     plugins.iter().map(|x| style(&x.name).bold()).join(", ")
   ));
 
-  pgx_stop_pg15()?;
+  pgx_stop(config.pg_version)?;
 
   let shared_preloads = edit_pgconf(&db, &config, &plugins)?;
-  pgx_start_pg15()?;
+  pgx_start(config.pg_version)?;
 
   let mut client = Client::connect_test_db()?;
   client.show_preload_libraries(println)?;
@@ -302,6 +302,7 @@ pub fn cmd_test(cmd: CmdTest, pbar: Option<ProgressBar>) -> Result<Vec<String>>
         Some((&installs, &shared_preloads)),
         &build_dir,
         &config.pg_config,
+        &config.pg_contrib,
       ) {
         println(format!("{err}"));
         println(format!(
@@ -313,7 +314,7 @@ pub fn cmd_test(cmd: CmdTest, pbar: Option<ProgressBar>) -> Result<Vec<String>>
       }
     }
   }
-  pgx_stop_pg15()?;
+  pgx_stop(config.pg_version)?;
 
   Ok(hooks)
 }
@@ -338,9 +339,9 @@ pub fn cmd_test_single(cmd: CmdTestSingle, pbar: Option<ProgressBar>) -> Result<
     style(&plugin.name).bold()
   ));
 
-  pgx_stop_pg15()?;
+  pgx_stop(config.pg_version)?;
   edit_pgconf(&db, &config, &[plugin.clone()])?;
-  pgx_start_pg15()?;
+  pgx_start(config.pg_version)?;
 
   let mut client = Client::connect_test_db()?;
   client.show_preload_libraries(println)?;
@@ -357,7 +358,7 @@ pub fn cmd_test_single(cmd: CmdTestSingle, pbar: Option<ProgressBar>) -> Result<
     let build_dir = workdir.join("builds").join(name_tag);
 
     println!("{} {}", style("Regression Testing").bold().blue(), plugin.name);
-    if let Err(err) = pgxs_installcheck(&plugin, None, &build_dir, &config.pg_config) {
+    if let Err(err) = pgxs_installcheck(&plugin, None, &build_dir, &config.pg_config, &config.pg_contrib) {
       println(format!("{err}"));
       println(format!(
         "{} - {}",
@@ -367,6 +368,6 @@ pub fn cmd_test_single(cmd: CmdTestSingle, pbar: Option<ProgressBar>) -> Result<
     }
   }
 
-  pgx_stop_pg15()?;
+  pgx_stop(config.pg_version)?;
   Ok(hooks)
 }
@@ -2,6 +2,7 @@ use std::fmt::Write as _;
 use std::path::PathBuf;
 
 use anyhow::{Context, Result};
+use duct::cmd;
 use serde::{Deserialize, Serialize};
 
 use crate::plugin::{collect_shared_preload_libraries, Plugin, PluginDb};
@@ -11,6 +12,27 @@ pub struct WorkspaceConfig {
   pub pg_config: String,
   pub pg_data: String,
   pub pg_contrib: String,
+  /// Major version of the Postgres instance
+  #[serde(default = "default_pg_version")]
+  pub pg_version: u32,
+}
+
+/// Workspaces created before the version was recorded all use Postgres 15
+fn default_pg_version() -> u32 {
+  15
+}
+
+/// Get the major version of Postgres from `pg_config --version`
+pub fn detect_pg_version(pg_config: &str) -> Result<u32> {
+  let version = cmd!(pg_config, "--version").read()?;
+  // e.g. "PostgreSQL 15.2" or "PostgreSQL 16beta1"
+  let major = version
+    .trim()
+    .strip_prefix("PostgreSQL ")
+    .and_then(|x| x.split(|c: char| !c.is_ascii_digit()).next())
+    .and_then(|x| x.parse().ok())
+    .with_context(|| format!("cannot parse pg_config version: {}", version))?;
+  Ok(major)
 }
 
 /// Load the workspace config
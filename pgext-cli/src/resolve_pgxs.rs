@@ -100,12 +100,13 @@ pub fn pgxs_installcheck(
   other: Option<(&Vec<String>, &Vec<String>)>,
   build_dir: &Path,
   pg_config: &str,
+  pg_contrib: &str,
 ) -> Result<()> {
   let pg_host = home::home_dir().unwrap().join(".pgrx");
   let final_path = if plugin.resolver.as_str() != "pgsrctree" {
     find_pgxs_path(build_dir)?
   } else {
-    Some(pg_host.join("15.2/contrib").join(plugin.name.clone()))
+    Some(PathBuf::from(pg_contrib).join(plugin.name.clone()))
   };
 
   if let Some(parent) = final_path {
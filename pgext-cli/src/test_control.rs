@@ -133,9 +133,9 @@ impl ExtTestControl for Client {
   }
 }
 
-/// Start the pgx-managed postgres 15 instance
-pub fn pgx_start_pg15() -> Result<()> {
-  let output = cmd!("cargo", "pgrx", "start", "pg15")
+/// Start the pgx-managed postgres instance of the given major version
+pub fn pgx_start(pg_version: u32) -> Result<()> {
+  let output = cmd!("cargo", "pgrx", "start", format!("pg{}", pg_version))
     .dir("pgx_show_hooks")
     .stderr_to_stdout()
     .stdout_capture()
@@ -144,16 +144,19 @@ pub fn pgx_start_pg15() -> Result<()> {
 
   if !output.status.success() {
     println!("{}", std::str::from_utf8(&output.stdout)?);
-    let log = home::home_dir().unwrap().join(".pgrx").join("15.log"); // TODO: pgx should support this
+    let log = home::home_dir()
+      .unwrap()
+      .join(".pgrx")
+      .join(format!("{}.log", pg_version)); // TODO: pgx should support this
     cmd!("tail", "-n", "50", log).run()?;
-    return Err(anyhow::anyhow!("Failed to start pg15"));
+    return Err(anyhow::anyhow!("Failed to start pg{}", pg_version));
   }
   Ok(())
 }
 
-/// Stop the pgx-managed postgres 15 instance
-pub fn pgx_stop_pg15() -> Result<()> {
-  cmd!("cargo", "pgrx", "stop", "pg15")
+/// Stop the pgx-managed postgres instance of the given major version
+pub fn pgx_stop(pg_version: u32) -> Result<()> {
+  cmd!("cargo", "pgrx", "stop", format!("pg{}", pg_version))
     .dir("pgx_show_hooks")
     .stderr_null()
     .stdout_null()
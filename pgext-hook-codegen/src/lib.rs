@@ -0,0 +1,404 @@
+//! Code generation for the hooks that pgextmgr chains through `HookMgr`.
+//!
+//! ```ignore
+//! chained_hooks! {
+//!   planner_hook: planner_hook_type => standard_planner,
+//!   ExecutorStart_hook: ExecutorStart_hook_type => standard_ExecutorStart,
+//! }
+//! ```
+//!
+//! Each line names a `pg_sys` hook variable, its `pg_sys::*_hook_type` and the
+//! function called once all plugins are done. For every hook this generates
+//! the function installed into PostgreSQL (`pgext_<hook>`), the function the
+//! plugins chain to (`pgext_<hook>_cb`), the pre-generated trampolines handed
+//! out to compatible plugins (`PREGENERATED_<HOOK>S`), and a `ChainedHooks`
+//! struct with one `HookMgr` per hook. Hook names are snake cased, e.g.
+//! `ExecutorStart_hook` becomes `executor_start_hook`. The chain is walked by
+//! `crate::hook_mgr::dispatch`, with the hook's arguments as its `Chain`.
+//! Plugin callbacks run through `crate::callback::run` as `<hook>`,
+//! `<hook>.before` or `<hook>.after`, skipped ones are reported to
+//! `crate::callback::skip`, and the rest of the chain runs through
+//! `crate::callback::outside`.
+
+use heck::{ToShoutySnakeCase, ToSnakeCase};
+use proc_macro::TokenStream;
+use proc_macro2::Span;
+use quote::{format_ident, quote};
+use syn::parse::{Parse, ParseStream};
+use syn::punctuated::Punctuated;
+use syn::{parse_macro_input, parse_quote, Ident, Path, ReturnType, Token, Type, TypeBareFn};
+
+/// Number of trampolines generated per hook, i.e. how many plugins may install
+/// the same hook in the original way.
+const PREGENERATED_COPIES: usize = 5;
+
+/// Signatures of the `pg_sys` hook types shared by all supported PostgreSQL
+/// versions, with the parameters renamed to snake case. Names that are not
+/// paths are resolved in `pg_sys`.
+const HOOK_TYPES: &[(&str, &str)] = &[
+  (
+    "planner_hook_type",
+    "fn(parse: *mut Query, query_string: *const ::std::os::raw::c_char, cursor_options: ::std::os::raw::c_int, \
+     bound_params: ParamListInfo) -> *mut PlannedStmt",
+  ),
+  (
+    "ExplainOneQuery_hook_type",
+    "fn(query: *mut Query, cursor_options: ::std::os::raw::c_int, into: *mut IntoClause, es: *mut ExplainState, \
+     query_string: *const ::std::os::raw::c_char, params: ParamListInfo, query_env: *mut QueryEnvironment)",
+  ),
+  (
+    "join_search_hook_type",
+    "fn(root: *mut PlannerInfo, levels_needed: ::std::os::raw::c_int, initial_rels: *mut List) -> *mut RelOptInfo",
+  ),
+  (
+    "set_rel_pathlist_hook_type",
+    "fn(root: *mut PlannerInfo, rel: *mut RelOptInfo, rti: Index, rte: *mut RangeTblEntry)",
+  ),
+  (
+    "set_join_pathlist_hook_type",
+    "fn(root: *mut PlannerInfo, joinrel: *mut RelOptInfo, outerrel: *mut RelOptInfo, innerrel: *mut RelOptInfo, \
+     jointype: JoinType, extra: *mut JoinPathExtraData)",
+  ),
+  (
+    "create_upper_paths_hook_type",
+    "fn(root: *mut PlannerInfo, stage: UpperRelationKind, input_rel: *mut RelOptInfo, output_rel: *mut RelOptInfo, \
+     extra: *mut ::std::os::raw::c_void)",
+  ),
+  (
+    "get_relation_info_hook_type",
+    "fn(root: *mut PlannerInfo, relation_object_id: Oid, inhparent: bool, rel: *mut RelOptInfo)",
+  ),
+  (
+    "get_relation_stats_hook_type",
+    "fn(root: *mut PlannerInfo, rte: *mut RangeTblEntry, attnum: AttrNumber, vardata: *mut VariableStatData) -> bool",
+  ),
+  (
+    "get_index_stats_hook_type",
+    "fn(root: *mut PlannerInfo, index_oid: Oid, indexattnum: AttrNumber, vardata: *mut VariableStatData) -> bool",
+  ),
+  (
+    "get_attavgwidth_hook_type",
+    "fn(relid: Oid, attnum: AttrNumber) -> int32",
+  ),
+  (
+    "explain_get_index_name_hook_type",
+    "fn(index_id: Oid) -> *const ::std::os::raw::c_char",
+  ),
+  (
+    "ExecutorStart_hook_type",
+    "fn(query_desc: *mut QueryDesc, eflags: ::std::os::raw::c_int)",
+  ),
+  (
+    "ExecutorRun_hook_type",
+    "fn(query_desc: *mut QueryDesc, direction: ScanDirection, count: uint64, execute_once: bool)",
+  ),
+  ("ExecutorFinish_hook_type", "fn(query_desc: *mut QueryDesc)"),
+  ("ExecutorEnd_hook_type", "fn(query_desc: *mut QueryDesc)"),
+  (
+    "ExecutorCheckPerms_hook_type",
+    "fn(range_table: *mut List, ereport_on_violation: bool) -> bool",
+  ),
+  (
+    "object_access_hook_type",
+    "fn(access: ObjectAccessType, class_id: Oid, object_id: Oid, sub_id: ::std::os::raw::c_int, arg: *mut \
+     ::std::os::raw::c_void)",
+  ),
+  (
+    "row_security_policy_hook_type",
+    "fn(cmdtype: CmdType, relation: Relation) -> *mut List",
+  ),
+  (
+    "check_password_hook_type",
+    "fn(username: *const ::std::os::raw::c_char, shadow_pass: *const ::std::os::raw::c_char, password_type: \
+     PasswordType, validuntil_time: Datum, validuntil_null: bool)",
+  ),
+  ("needs_fmgr_hook_type", "fn(fn_oid: Oid) -> bool"),
+  (
+    "fmgr_hook_type",
+    "fn(event: FmgrHookEventType, flinfo: *mut FmgrInfo, arg: *mut Datum)",
+  ),
+  ("emit_log_hook_type", "fn(edata: *mut ErrorData)"),
+  ("shmem_startup_hook_type", "fn()"),
+];
+
+/// Hook types whose signature differs between versions.
+#[cfg(feature = "pg13")]
+const VERSION_HOOK_TYPES: &[(&str, &str)] = &[
+  (
+    "post_parse_analyze_hook_type",
+    "fn(pstate: *mut ParseState, query: *mut Query)",
+  ),
+  (
+    "ProcessUtility_hook_type",
+    "fn(pstmt: *mut PlannedStmt, query_string: *const ::std::os::raw::c_char, context: ProcessUtilityContext, params: \
+     ParamListInfo, query_env: *mut QueryEnvironment, dest: *mut DestReceiver, qc: *mut QueryCompletion)",
+  ),
+];
+
+/// Hook types whose signature differs between versions.
+#[cfg(not(feature = "pg13"))]
+const VERSION_HOOK_TYPES: &[(&str, &str)] = &[
+  (
+    "post_parse_analyze_hook_type",
+    "fn(pstate: *mut ParseState, query: *mut Query, jstate: *mut JumbleState)",
+  ),
+  (
+    "ProcessUtility_hook_type",
+    "fn(pstmt: *mut PlannedStmt, query_string: *const ::std::os::raw::c_char, read_only_tree: bool, context: \
+     ProcessUtilityContext, params: ParamListInfo, query_env: *mut QueryEnvironment, dest: *mut DestReceiver, qc: \
+     *mut QueryCompletion)",
+  ),
+  #[cfg(not(feature = "pg14"))]
+  ("shmem_request_hook_type", "fn()"),
+];
+
+/// `<hook>: <hook type> => <fallback>`
+struct ChainedHook {
+  hook: Ident,
+  hook_type: Ident,
+  fallback: Path,
+}
+
+impl Parse for ChainedHook {
+  fn parse(input: ParseStream) -> syn::Result<Self> {
+    let hook = input.parse()?;
+    input.parse::<Token![:]>()?;
+    let hook_type = input.parse()?;
+    input.parse::<Token![=>]>()?;
+    let fallback = input.parse()?;
+    Ok(Self {
+      hook,
+      hook_type,
+      fallback,
+    })
+  }
+}
+
+/// Resolve the bare type names of a signature in `pg_sys`.
+fn qualify(ty: &mut Type) {
+  match ty {
+    Type::Ptr(ptr) => qualify(&mut ptr.elem),
+    Type::Path(path) if path.qself.is_none() && path.path.leading_colon.is_none() => {
+      let name = &path.path.segments[0].ident;
+      if name != "bool" {
+        *ty = parse_quote!(pgrx::pg_sys::#name);
+      }
+    }
+    _ => {}
+  }
+}
+
+fn signature(hook_type: &Ident) -> syn::Result<(Vec<Ident>, Vec<Type>, ReturnType)> {
+  let Some((_, signature)) = HOOK_TYPES
+    .iter()
+    .chain(VERSION_HOOK_TYPES)
+    .find(|(name, _)| hook_type == name)
+  else {
+    return Err(syn::Error::new(
+      hook_type.span(),
+      format!("unknown hook type {}, add its signature to HOOK_TYPES", hook_type),
+    ));
+  };
+  let signature: TypeBareFn = syn::parse_str(signature)?;
+  let mut names = vec![];
+  let mut types = vec![];
+  for arg in signature.inputs {
+    let (name, _) = arg.name.expect("parameters of hook types are named");
+    let mut ty = arg.ty;
+    qualify(&mut ty);
+    names.push(name);
+    types.push(ty);
+  }
+  let mut output = signature.output;
+  if let ReturnType::Type(_, ty) = &mut output {
+    qualify(ty);
+  }
+  Ok((names, types, output))
+}
+
+fn expand(hooks: Punctuated<ChainedHook, Token![,]>) -> syn::Result<proc_macro2::TokenStream> {
+  let mut items = vec![];
+  let mut fields = vec![];
+  let mut field_types = vec![];
+  let mut pregenerated = vec![];
+  let mut globals = vec![];
+  let mut entries = vec![];
+
+  for ChainedHook {
+    hook,
+    hook_type,
+    fallback,
+  } in hooks
+  {
+    let (names, types, output) = signature(&hook_type)?;
+    let field = Ident::new(&hook.to_string().to_snake_case(), Span::call_site());
+    let upper = field.to_string().to_shouty_snake_case();
+    let depth = format_ident!("{}_NESTED_DEPTH", upper);
+    let entry = format_ident!("pgext_{}", field);
+    let cb = format_ident!("pgext_{}_cb", field);
+    let global = format_ident!("PREGENERATED_{}S", upper);
+    let trampolines = (1..=PREGENERATED_COPIES)
+      .map(|id| format_ident!("__pgext_{}_{}", field, id))
+      .collect::<Vec<_>>();
+    let ids = 1..=PREGENERATED_COPIES;
+    let name = field.to_string();
+    let before_name = format!("{}.before", name);
+    let after_name = format!("{}.after", name);
+    let params = quote!(#(#names: #types),*);
+    let ret = match &output {
+      ReturnType::Default => quote!(()),
+      ReturnType::Type(_, ty) => quote!(#ty),
+    };
+    let args = quote!(#(#names),*);
+
+    items.push(quote! {
+      pub(crate) static mut #depth: usize = 0;
+
+      /// Postgres will directly call this hook.
+      #[pgrx::pg_guard]
+      pub unsafe extern "C" fn #entry(#params) #output {
+        pgrx::PgTryBuilder::new(|| {
+          #depth += 1;
+          #cb(0, #args)
+        })
+        .finally(|| {
+          #depth -= 1;
+        })
+        .execute()
+      }
+
+      /// All extensions will call this hook after finishing their own work.
+      pub unsafe fn #cb(id: usize, #params) #output {
+        /// The arguments of the hook, passed along the chain.
+        #[derive(Clone, Copy)]
+        struct Args {
+          #(#names: #types),*
+        }
+
+        impl crate::hook_mgr::Chain<::std::string::String, pgrx::pg_sys::#hook_type> for Args {
+          type Output = #ret;
+
+          fn compatible(&mut self, plugin: &::std::string::String, hook: pgrx::pg_sys::#hook_type) -> #ret {
+            let Args { #args } = *self;
+            if crate::ENABLE_LOGGING {
+              pgrx::info!("{}: {} (compatible)", #name, plugin);
+            }
+            // the hook calls the next extension through its pre-generated hook
+            unsafe { crate::callback::run(#name, plugin, || hook.unwrap()(#args)) }
+          }
+
+          fn before(&mut self, plugin: &::std::string::String, hook: pgrx::pg_sys::#hook_type) {
+            let Args { #args } = *self;
+            if crate::ENABLE_LOGGING {
+              pgrx::info!("{}: {} (pgext)", #name, plugin);
+            }
+            unsafe { crate::callback::run(#before_name, plugin, || hook.unwrap()(#args)) };
+          }
+
+          fn after(&mut self, plugin: &::std::string::String, hook: pgrx::pg_sys::#hook_type) -> #ret {
+            let Args { #args } = *self;
+            unsafe { crate::callback::run(#after_name, plugin, || hook.unwrap()(#args)) }
+          }
+
+          fn skip(&mut self, plugin: &::std::string::String) {
+            unsafe { crate::callback::skip(#name, plugin) }
+          }
+
+          fn next(&mut self, id: usize) -> #ret {
+            let Args { #args } = *self;
+            unsafe { #cb(id, #args) }
+          }
+
+          fn fallback(&mut self) -> #ret {
+            let Args { #args } = *self;
+            unsafe { #fallback(#args) }
+          }
+        }
+
+        crate::callback::outside(|| {
+          crate::hook_mgr::dispatch(
+            crate::hook_mgr::ALL_HOOKS.chained.#field.hooks(),
+            id,
+            |plugin| crate::callback::enabled(plugin),
+            &mut Args { #args },
+          )
+        })
+      }
+
+      #(
+        #[pgrx::pg_guard]
+        unsafe extern "C" fn #trampolines(#params) #output {
+          #cb(#ids, #args)
+        }
+      )*
+
+      pub static #global: &[pgrx::pg_sys::#hook_type] = &[#(Some(#trampolines)),*];
+    });
+    fields.push(field);
+    field_types.push(hook_type);
+    pregenerated.push(global);
+    globals.push(hook);
+    entries.push(entry);
+  }
+
+  let names = fields.iter().map(|field| field.to_string());
+  Ok(quote! {
+    #(#items)*
+
+    /// The hooks that plugins chain through pgextmgr.
+    pub struct ChainedHooks {
+      #(pub #fields: crate::hook_mgr::HookMgr<::std::string::String, pgrx::pg_sys::#field_types>,)*
+    }
+
+    impl ChainedHooks {
+      pub const fn new() -> Self {
+        Self {
+          #(#fields: crate::hook_mgr::HookMgr::new(#pregenerated),)*
+        }
+      }
+
+      /// Hand out the next pre-generated hooks before a plugin is loaded.
+      pub unsafe fn before_init(&mut self) {
+        #(
+          pgrx::pg_sys::#globals = self.#fields.before_register(Some(#entries), pgrx::pg_sys::#globals);
+        )*
+      }
+
+      /// Pick up the hooks a plugin installed in the original way.
+      pub unsafe fn after_init(&mut self, plugin: &str) {
+        #(
+          pgrx::pg_sys::#globals = self.#fields.after_register(plugin.to_string(), pgrx::pg_sys::#globals);
+        )*
+      }
+
+      pub fn unregister(&mut self, plugin: &str) {
+        let plugin = plugin.to_string();
+        #(self.#fields.unregister(&plugin);)*
+      }
+
+      /// (hook, order, plugin) of every registered hook.
+      pub fn registered(&self) -> Vec<(::std::string::String, i64, ::std::string::String)> {
+        let mut data = vec![];
+        #(
+          data.extend(
+            self
+              .#fields
+              .hooks()
+              .iter()
+              .filter(|(_, hook)| !matches!(hook, crate::hook_mgr::HookType::Unregistered))
+              .enumerate()
+              .map(|(id, (plugin, _))| (#names.to_string(), id as i64, plugin.clone())),
+          );
+        )*
+        data
+      }
+    }
+  })
+}
+
+/// Generate the chained hooks of pgextmgr, see the crate documentation.
+#[proc_macro]
+pub fn chained_hooks(input: TokenStream) -> TokenStream {
+  let hooks = parse_macro_input!(input with Punctuated::<ChainedHook, Token![,]>::parse_terminated);
+  expand(hooks).unwrap_or_else(syn::Error::into_compile_error).into()
+}
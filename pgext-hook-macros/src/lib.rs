@@ -1,14 +1,14 @@
 #[macro_export]
 macro_rules! for_all_hooks {
   ($macro:ident) => {
-    $macro! {
+    $crate::__with_version_hooks! { $macro {
       // General Hooks
       emit_log_hook,
       shmem_startup_hook,
 
       // Security Hooks
       check_password_hook,
-      // ClientAuthentication_hook,
+      ClientAuthentication_hook,
       ExecutorCheckPerms_hook,
       object_access_hook,
       row_security_policy_hook_permissive,
@@ -38,81 +38,39 @@ macro_rules! for_all_hooks {
       ExecutorFinish_hook,
       ExecutorEnd_hook,
       ProcessUtility_hook,
-    }
-  };
-}
-
-#[macro_export]
-macro_rules! for_all_plpgsql_hooks {
-  ($macro:ident) => {
-    $macro! {
-      func_setup,
-      func_beg,
-      func_end,
-      stmt_beg,
-      stmt_end,
-    }
-  };
-}
-
-#[macro_export]
-macro_rules! planner_hook_params {
-  ([ $($x:tt),* ] $macro:ident) => {
-    $macro! {
-      [ $($x),* ] {
-        parse: *mut pgrx::pg_sys::Query,
-        query_string: *const ::std::os::raw::c_char,
-        cursor_options: ::std::os::raw::c_int,
-        bound_params: pgrx::pg_sys::ParamListInfo,
-      }
-    }
+    } }
   };
 }
 
+/// Append the hooks that only exist in some PostgreSQL versions.
+#[cfg(not(any(feature = "pg13", feature = "pg14")))]
+#[doc(hidden)]
 #[macro_export]
-macro_rules! executor_start_hook_params {
-  ([ $($x:tt),* ] $macro:ident) => {
-    $macro! {
-      [ $($x),* ] {
-        query_desc: *mut QueryDesc,
-        eflags: c_int,
-      }
-    }
+macro_rules! __with_version_hooks {
+  ($macro:ident { $($hook:ident,)* }) => {
+    $macro! { $($hook,)* shmem_request_hook, }
   };
 }
 
+/// Append the hooks that only exist in some PostgreSQL versions.
+#[cfg(any(feature = "pg13", feature = "pg14"))]
+#[doc(hidden)]
 #[macro_export]
-macro_rules! executor_run_hook_params {
-  ([ $($x:tt),* ] $macro:ident) => {
-    $macro! {
-      [ $($x),* ] {
-        query_desc: *mut QueryDesc,
-        direction: ScanDirection,
-        count: uint64,
-        execute_once: bool,
-      }
-    }
+macro_rules! __with_version_hooks {
+  ($macro:ident { $($hook:ident,)* }) => {
+    $macro! { $($hook,)* }
   };
 }
 
 #[macro_export]
-macro_rules! executor_finish_hook_params {
-  ([ $($x:tt),* ] $macro:ident) => {
-    $macro! {
-      [ $($x),* ] {
-        query_desc: *mut QueryDesc,
-      }
-    }
-  };
-}
-
-#[macro_export]
-macro_rules! executor_end_hook_params {
-  ([ $($x:tt),* ] $macro:ident) => {
+macro_rules! for_all_plpgsql_hooks {
+  ($macro:ident) => {
     $macro! {
-      [ $($x),* ] {
-        query_desc: *mut QueryDesc,
-      }
+      func_setup,
+      func_beg,
+      func_end,
+      stmt_beg,
+      stmt_end,
     }
   };
 }
@@ -0,0 +1,393 @@
+//! Bookkeeping of the hooks that pgextmgr installs on behalf of plugins.
+//!
+//! This crate does not depend on pgrx, so that the order in which plugin hooks
+//! are registered and called can be tested with plain `cargo test`. The hook
+//! function types are generic: pgextmgr instantiates them with the `pg_sys`
+//! hook types, the tests with mock ones.
+
+pub enum HookType<T> {
+  /// Compatible mode, when extensions are using the original way of registering
+  /// hooks.
+  Compatible(T),
+  /// PgExt mode, where extensions registers before and after hooks.
+  PgExt(T, T),
+  /// The extension has been unregistered. The entry is kept so that the
+  /// pre-generated hooks of the remaining extensions keep their ids.
+  Unregistered,
+}
+
+pub struct HookMgr<P: Clone, T: Copy + Clone + PartialEq + Eq + 'static> {
+  available_callbacks: &'static [T],
+  hooks: Vec<(P, HookType<T>)>,
+  next_hook_id: usize,
+  registered: bool,
+  prev_hook: Option<(T, T)>,
+}
+
+impl<P: Clone, T: Copy + Clone + PartialEq + Eq + 'static> HookMgr<P, T> {
+  pub const fn new(available_callbacks: &'static [T]) -> Self {
+    Self {
+      available_callbacks,
+      hooks: Vec::new(),
+      next_hook_id: 0,
+      registered: false,
+      prev_hook: None,
+    }
+  }
+
+  pub fn before_register(&mut self, override_with: T, prev_hook: T) -> T {
+    if let Some(hook) = self.available_callbacks.get(self.next_hook_id) {
+      self.registered = false;
+      self.next_hook_id += 1;
+      self.prev_hook = Some((override_with, prev_hook));
+      *hook
+    } else {
+      panic!("too many extensions")
+    }
+  }
+
+  pub fn after_register(&mut self, plugin: P, hook: T) -> T {
+    let (override_with, prev_hook) = self.prev_hook.take().unwrap();
+    if hook == self.available_callbacks[self.next_hook_id - 1] {
+      if self.registered {
+        return override_with;
+      } else {
+        // the extension is not using this hook
+        self.next_hook_id -= 1;
+        return prev_hook;
+      }
+    }
+    assert!(!self.registered, "extension registered twice");
+    self.hooks.push((plugin, HookType::Compatible(hook)));
+    override_with
+  }
+
+  pub fn register(&mut self, plugin: P, before: T, after: T) {
+    assert!(!self.registered, "extension registered twice");
+    self.registered = true;
+    self.hooks.push((plugin, HookType::PgExt(before, after)));
+  }
+
+  // TODO: support register compatible hook, should return a callback function
+  // pub fn register_compatible(&mut self, plugin: P, hook: T) {
+  //   assert!(!self.registered, "extension registered twice");
+  //   self.registered = true;
+  //   self.hooks.push((plugin, HookType::Compatible(hook)));
+  // }
+
+  /// Remove all hooks registered by the plugin from the chain.
+  pub fn unregister(&mut self, plugin: &P)
+  where
+    P: PartialEq,
+  {
+    for (name, hook) in self.hooks.iter_mut() {
+      if name == plugin {
+        *hook = HookType::Unregistered;
+      }
+    }
+  }
+
+  pub fn hooks(&self) -> &[(P, HookType<T>)] {
+    &self.hooks
+  }
+}
+
+/// Hooks that pgextmgr calls for every plugin in turn, instead of letting the
+/// plugins chain them. `D` is per-plugin data kept next to the hook (e.g. a
+/// filter).
+pub struct FanOutHookMgr<T: Copy + Default + PartialEq + 'static, D: Default> {
+  hooks: Vec<(std::string::String, T, D)>,
+  prev_hook: Option<(T, T)>,
+  /// The hook installed before pgextmgr took over, e.g. by a library loaded
+  /// earlier.
+  prev: Option<T>,
+}
+
+impl<T: Copy + Default + PartialEq + 'static, D: Default> FanOutHookMgr<T, D> {
+  pub const fn new() -> Self {
+    Self {
+      hooks: Vec::new(),
+      prev_hook: None,
+      prev: None,
+    }
+  }
+
+  /// Clear the hook before the extension is loaded, so that we can tell whether
+  /// it installs one in the original way.
+  pub fn before_register(&mut self, override_with: T, prev_hook: T) -> T {
+    if prev_hook != override_with {
+      self.prev = Some(prev_hook);
+    }
+    self.prev_hook = Some((override_with, prev_hook));
+    T::default()
+  }
+
+  pub fn after_register(&mut self, plugin: std::string::String, hook: T) -> T {
+    let (override_with, prev_hook) = self.prev_hook.take().unwrap();
+    if hook != T::default() {
+      self.hooks.push((plugin, hook, D::default()));
+    }
+    if self.hooks.is_empty() {
+      prev_hook
+    } else {
+      override_with
+    }
+  }
+
+  pub fn register(&mut self, plugin: std::string::String, hook: T, data: D) {
+    self.hooks.push((plugin, hook, data));
+  }
+
+  /// Remove the plugin's hook. The entry is kept with an empty hook, so that
+  /// state kept by position (e.g. for `fmgr_hook`) stays valid.
+  pub fn unregister(&mut self, plugin: &str) {
+    for (name, hook, _) in self.hooks.iter_mut() {
+      if name == plugin {
+        *hook = T::default();
+      }
+    }
+  }
+
+  pub fn hooks(&self) -> &[(std::string::String, T, D)] {
+    &self.hooks
+  }
+
+  /// The hook that was installed before pgextmgr, which the fan-out calls
+  /// before the plugins once pgextmgr has replaced it.
+  pub fn prev(&self) -> T {
+    self.prev.unwrap_or_default()
+  }
+
+  /// The hooks that have not been unregistered.
+  pub fn registered(&self) -> impl Iterator<Item = &(std::string::String, T, D)> {
+    self.hooks.iter().filter(|(_, hook, _)| *hook != T::default())
+  }
+}
+
+impl<T: Copy + Default + PartialEq + 'static, D: Default> Default for FanOutHookMgr<T, D> {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
+/// The calls a chained hook makes while walking the plugins, see `dispatch`.
+pub trait Chain<P, T> {
+  type Output;
+
+  /// Call a hook the plugin installed in the original way. It calls the rest
+  /// of the chain itself, through its pre-generated hook.
+  fn compatible(&mut self, plugin: &P, hook: T) -> Self::Output;
+  fn before(&mut self, plugin: &P, hook: T);
+  fn after(&mut self, plugin: &P, hook: T) -> Self::Output;
+  /// Pass over a disabled plugin.
+  fn skip(&mut self, plugin: &P);
+  /// Continue with the hook at `id`.
+  fn next(&mut self, id: usize) -> Self::Output;
+  /// Called once all plugins are done.
+  fn fallback(&mut self) -> Self::Output;
+}
+
+/// Call the hook at `id` of the chain, and through it the rest of the chain.
+pub fn dispatch<P, T: Copy, C: Chain<P, T>>(
+  hooks: &[(P, HookType<T>)],
+  id: usize,
+  enabled: impl Fn(&P) -> bool,
+  chain: &mut C,
+) -> C::Output {
+  match hooks.get(id) {
+    Some((plugin, HookType::Compatible(hook))) if enabled(plugin) => chain.compatible(plugin, *hook),
+    Some((plugin, HookType::PgExt(before, after))) if enabled(plugin) => {
+      chain.before(plugin, *before);
+      chain.next(id + 1);
+      chain.after(plugin, *after)
+    }
+    Some((_, HookType::Unregistered)) => chain.next(id + 1),
+    // disabled, skip
+    Some((plugin, _)) => {
+      chain.skip(plugin);
+      chain.next(id + 1)
+    }
+    None => chain.fallback(),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  /// A hook is represented by a number, 0 being the empty hook.
+  type Hook = u32;
+
+  /// Pre-generated hooks handed out to compatible plugins.
+  static PREGENERATED: &[Hook] = &[101, 102, 103];
+  /// The hook pgextmgr installs itself.
+  const ENTRY: Hook = 100;
+
+  /// Load a plugin, which installs `installs` in the original way if any, and
+  /// return the hook left in place.
+  fn load(
+    mgr: &mut HookMgr<&'static str, Hook>,
+    installed: Hook,
+    plugin: &'static str,
+    installs: Option<Hook>,
+  ) -> Hook {
+    let current = mgr.before_register(ENTRY, installed);
+    mgr.after_register(plugin, installs.unwrap_or(current))
+  }
+
+  #[test]
+  fn test_unused_hook() {
+    let mut mgr = HookMgr::new(PREGENERATED);
+    assert_eq!(load(&mut mgr, 0, "a", None), 0);
+    assert!(mgr.hooks().is_empty());
+    // the pre-generated hook is handed out again
+    assert_eq!(mgr.before_register(ENTRY, 0), 101);
+  }
+
+  #[test]
+  fn test_compatible_and_pgext() {
+    let mut mgr = HookMgr::new(PREGENERATED);
+    assert_eq!(load(&mut mgr, 0, "a", Some(7)), ENTRY);
+    mgr.before_register(ENTRY, ENTRY);
+    mgr.register("b", 8, 9);
+    assert_eq!(mgr.after_register("b", 102), ENTRY);
+    let hooks = mgr
+      .hooks()
+      .iter()
+      .map(|(plugin, hook)| match hook {
+        HookType::Compatible(hook) => (*plugin, *hook, 0),
+        HookType::PgExt(before, after) => (*plugin, *before, *after),
+        HookType::Unregistered => (*plugin, 0, 0),
+      })
+      .collect::<Vec<_>>();
+    assert_eq!(hooks, vec![("a", 7, 0), ("b", 8, 9)]);
+  }
+
+  #[test]
+  #[should_panic(expected = "extension registered twice")]
+  fn test_register_twice() {
+    let mut mgr = HookMgr::new(PREGENERATED);
+    mgr.before_register(ENTRY, 0);
+    mgr.register("a", 8, 9);
+    mgr.register("a", 8, 9);
+  }
+
+  #[test]
+  #[should_panic(expected = "extension registered twice")]
+  fn test_register_and_install() {
+    let mut mgr = HookMgr::new(PREGENERATED);
+    mgr.before_register(ENTRY, 0);
+    mgr.register("a", 8, 9);
+    mgr.after_register("a", 7);
+  }
+
+  #[test]
+  #[should_panic(expected = "too many extensions")]
+  fn test_too_many_extensions() {
+    let mut mgr = HookMgr::new(PREGENERATED);
+    for (i, plugin) in ["a", "b", "c", "d"].into_iter().enumerate() {
+      load(&mut mgr, ENTRY, plugin, Some(i as Hook + 1));
+    }
+  }
+
+  #[test]
+  fn test_fan_out_keeps_prev_hook() {
+    const FAN_OUT: Hook = 100;
+    let mut mgr = FanOutHookMgr::<Hook, ()>::new();
+    // a library loaded before pgextmgr installed hook 5
+    assert_eq!(mgr.before_register(FAN_OUT, 5), 0);
+    assert_eq!(mgr.after_register("a".to_string(), 0), 5);
+    assert_eq!(mgr.prev(), 5);
+    assert_eq!(mgr.before_register(FAN_OUT, 5), 0);
+    assert_eq!(mgr.after_register("b".to_string(), 7), FAN_OUT);
+    mgr.before_register(FAN_OUT, FAN_OUT);
+    mgr.register("c".to_string(), 8, ());
+    assert_eq!(mgr.after_register("c".to_string(), 0), FAN_OUT);
+    assert_eq!(mgr.prev(), 5);
+    let hooks = mgr
+      .hooks()
+      .iter()
+      .map(|(plugin, hook, _)| (plugin.as_str(), *hook))
+      .collect::<Vec<_>>();
+    assert_eq!(hooks, vec![("b", 7), ("c", 8)]);
+  }
+
+  /// Records the calls made while walking the chain. Compatible hooks call the
+  /// rest of the chain through the pre-generated hook of their position.
+  struct Recorder<'a> {
+    hooks: &'a [(&'static str, HookType<Hook>)],
+    disabled: &'a [&'static str],
+    calls: Vec<String>,
+  }
+
+  impl Recorder<'_> {
+    fn run(&mut self, id: usize) {
+      let (hooks, disabled) = (self.hooks, self.disabled);
+      dispatch(hooks, id, |plugin| !disabled.contains(plugin), self)
+    }
+  }
+
+  impl Chain<&'static str, Hook> for Recorder<'_> {
+    type Output = ();
+
+    fn compatible(&mut self, plugin: &&'static str, hook: Hook) {
+      self.calls.push(format!("{} {}", plugin, hook));
+      let id = self.hooks.iter().position(|(p, _)| p == plugin).unwrap();
+      self.run(id + 1);
+    }
+
+    fn before(&mut self, plugin: &&'static str, hook: Hook) {
+      self.calls.push(format!("{} before {}", plugin, hook));
+    }
+
+    fn after(&mut self, plugin: &&'static str, hook: Hook) {
+      self.calls.push(format!("{} after {}", plugin, hook));
+    }
+
+    fn skip(&mut self, plugin: &&'static str) {
+      self.calls.push(format!("{} skipped", plugin));
+    }
+
+    fn next(&mut self, id: usize) {
+      self.run(id);
+    }
+
+    fn fallback(&mut self) {
+      self.calls.push("standard".to_string());
+    }
+  }
+
+  fn walk(mgr: &HookMgr<&'static str, Hook>, disabled: &[&'static str]) -> Vec<String> {
+    let mut recorder = Recorder {
+      hooks: mgr.hooks(),
+      disabled,
+      calls: vec![],
+    };
+    recorder.run(0);
+    recorder.calls
+  }
+
+  #[test]
+  fn test_dispatch_order() {
+    let mut mgr = HookMgr::new(PREGENERATED);
+    mgr.before_register(ENTRY, 0);
+    mgr.register("a", 1, 2);
+    mgr.after_register("a", 101);
+    load(&mut mgr, ENTRY, "b", Some(3));
+    mgr.before_register(ENTRY, ENTRY);
+    mgr.register("c", 4, 5);
+    mgr.after_register("c", 103);
+
+    assert_eq!(
+      walk(&mgr, &[]),
+      ["a before 1", "b 3", "c before 4", "standard", "c after 5", "a after 2"]
+    );
+    assert_eq!(
+      walk(&mgr, &["a", "b"]),
+      ["a skipped", "b skipped", "c before 4", "standard", "c after 5"]
+    );
+
+    mgr.unregister(&"c");
+    assert_eq!(walk(&mgr, &[]), ["a before 1", "b 3", "standard", "a after 2"]);
+  }
+}
@@ -14,20 +14,23 @@ PG_MODULE_MAGIC;
 
 static struct PgExtApi *api;
 
+/* slot_set_attr copies the value, and pgextmgr frees the copy once the row has been sent. */
 static bool poopReceiveSlot(void *self, TupleTableSlot *slot, void *ctx,
                             bool (*cb)(void *)) {
   TupleDesc typeinfo = slot->tts_tupleDescriptor;
   int natts = typeinfo->natts;
-  bytea **bb = palloc_array(bytea *, natts);
 
   for (int i = 0; i < natts; ++i) {
-    bb[i] = NULL;
     Oid ty = TupleDescAttr(typeinfo, i)->atttypid;
     if (ty != VARCHAROID && ty != TEXTOID) {
       continue;
     }
+    Datum attr;
     bool isnull;
-    Datum attr = slot_getattr(slot, i + 1, &isnull);
+    api->slot_get_attr(api, slot, i, ty, &attr, &isnull);
+    if (isnull) {
+      continue;
+    }
     int64 vallen = toast_raw_datum_size(attr) - VARHDRSZ;
 
     const char *poop_emoji = "\360\237\222\251";
@@ -38,26 +41,16 @@ static bool poopReceiveSlot(void *self, TupleTableSlot *slot, void *ctx,
     for (int j = 0; j < vallen; j += 1) {
       memcpy(VARDATA(b) + j * poop_emoji_len, poop_emoji, poop_emoji_len);
     }
-    bb[i] = b;
-
-    slot->tts_values[i] = PointerGetDatum(b);
-  }
 
-  bool res = cb(ctx);
-
-  for (int i = 0; i < natts; ++i) {
-    if (bb[i]) {
-      pfree(bb[i]);
-    }
+    api->slot_set_attr(api, slot, i, ty, PointerGetDatum(b), false);
+    pfree(b);
   }
 
-  pfree(bb);
-
-  return res;
+  return cb(ctx);
 }
 
 void _PG_init(void) {
-  api = __pgext_before_init("pgext_pg_poop");
+  api = pgext_before_init("pgext_pg_poop");
   OutputRewriter r;
   r.destroy = NULL;
   r.startup = NULL;
@@ -65,5 +58,5 @@ void _PG_init(void) {
   r.shutdown = NULL;
   r.receive_slot = poopReceiveSlot;
   api->register_output_rewriter(api, &r);
-  __pgext_after_init();
+  pgext_after_init();
 }
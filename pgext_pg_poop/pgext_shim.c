@@ -0,0 +1 @@
+../pgextmgr/pgext_shim.c
\ No newline at end of file
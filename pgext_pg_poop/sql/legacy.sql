@@ -0,0 +1,3 @@
+\pset format unaligned
+-- without pgextmgr, the output rewriter runs from ExecutorRun_hook
+SELECT 'abc'::text AS t, 42 AS n;
@@ -0,0 +1,639 @@
+/*
+ * pgext_shim.c
+ *
+ * Lets a plugin written against pgextmgr.h load with or without pgextmgr.
+ * Link this file into the plugin and call pgext_before_init and
+ * pgext_after_init instead of the double-underscore functions. If pgextmgr
+ * has been loaded before the plugin, both calls are forwarded to it.
+ * Otherwise the plugin gets an api that installs everything with the plain
+ * PostgreSQL hooks, as if it had been written without the framework:
+ *
+ *  - output rewriters wrap the DestReceiver from ExecutorRun_hook, with the
+ *    same per-row virtual slot and memory context as under pgextmgr
+ *  - GUCs, LWLock tranches and shared memory are prefixed with the plugin name
+ *  - emit_log hooks, xact callbacks and background workers are registered
+ *    directly
+ *  - the query id is the one PostgreSQL reports for the backend, and there is
+ *    no normalized query text
+ *  - annotations, dependencies, conflicts, EXPLAIN callbacks and custom scan
+ *    providers only mean something to pgextmgr and are ignored
+ */
+#include "postgres.h"
+
+#include "access/xact.h"
+#include "executor/executor.h"
+#include "fmgr.h"
+#include "miscadmin.h"
+#include "nodes/pg_list.h"
+#include "postmaster/bgworker.h"
+#include "storage/ipc.h"
+#include "storage/lwlock.h"
+#include "storage/shmem.h"
+#include "tcop/dest.h"
+#include "utils/builtins.h"
+#if PG_VERSION_NUM >= 140000
+#include "utils/backend_status.h"
+#endif
+#include "utils/datum.h"
+#include "utils/guc.h"
+#include "utils/lsyscache.h"
+#include "utils/memutils.h"
+
+#include "pgextmgr.h"
+
+/* Same CommandDest pgextmgr uses for its output rewriter receiver. */
+#define PGEXT_SHIM_DEST ((CommandDest)2333)
+
+typedef struct ShimDestReceiver {
+  DestReceiver pub;
+  DestReceiver *original;
+  int nrewriters;
+  OutputRewriter *rewriters; /* the rewriters whose filter accepted the query */
+  void **states;             /* what their startup returned */
+  TupleTableSlot *slot;      /* virtual copy of the row, between startup and shutdown */
+  MemoryContext row_context; /* reset after every row */
+} ShimDestReceiver;
+
+typedef struct ShimReceiveContext {
+  ShimDestReceiver *dest;
+  TupleTableSlot *slot;
+  int next;
+  MemoryContext caller_context;
+} ShimReceiveContext;
+
+typedef struct ShimLogHook {
+  emit_log_hook_type hook;
+  int min_elevel;
+  int *sqlstates;
+  int nsqlstates;
+} ShimLogHook;
+
+typedef struct ShimShmemSegment {
+  char *name;
+  Size size;
+  ShmemStartup startup;
+} ShimShmemSegment;
+
+typedef struct ShimTranche {
+  char *name;
+  int num_locks;
+} ShimTranche;
+
+static char *shim_plugin = NULL;
+static bool shim_defined_gucs = false;
+
+static List *shim_rewriters = NIL;     /* OutputRewriter * */
+static List *shim_log_hooks = NIL;     /* ShimLogHook * */
+static List *shim_shmem_segments = NIL; /* ShimShmemSegment * */
+static List *shim_tranches = NIL;      /* ShimTranche * */
+
+static int shim_executor_run_depth = 0;
+
+static ExecutorRun_hook_type prev_ExecutorRun = NULL;
+static emit_log_hook_type prev_emit_log_hook = NULL;
+#if PG_VERSION_NUM >= 150000
+static shmem_request_hook_type prev_shmem_request_hook = NULL;
+#endif
+static shmem_startup_hook_type prev_shmem_startup_hook = NULL;
+
+static char *shim_full_name(const char *name) {
+  return psprintf("%s.%s", shim_plugin, name);
+}
+
+/* Output rewriters */
+
+static bool shim_receive_next(void *arg) {
+  ShimReceiveContext *ctx = (ShimReceiveContext *)arg;
+  ShimDestReceiver *dest = ctx->dest;
+
+  while (ctx->next < dest->nrewriters) {
+    int i = ctx->next;
+    ShimReceiveContext next = {dest, ctx->slot, i + 1, ctx->caller_context};
+
+    if (dest->rewriters[i].receive_slot == NULL) {
+      ctx->next++;
+      continue;
+    }
+    return dest->rewriters[i].receive_slot(dest->states[i], ctx->slot, &next, shim_receive_next);
+  }
+  {
+    MemoryContext oldcxt = MemoryContextSwitchTo(ctx->caller_context);
+    bool result = dest->original->receiveSlot(ctx->slot, dest->original);
+
+    MemoryContextSwitchTo(oldcxt);
+    return result;
+  }
+}
+
+static bool shim_receive_slot(TupleTableSlot *slot, DestReceiver *self) {
+  ShimDestReceiver *dest = (ShimDestReceiver *)self;
+  int natts = slot->tts_tupleDescriptor->natts;
+  ShimReceiveContext ctx = {dest, dest->slot, 0, CurrentMemoryContext};
+  MemoryContext oldcxt;
+  bool result;
+
+  slot_getallattrs(slot);
+  ExecClearTuple(dest->slot);
+  memcpy(dest->slot->tts_values, slot->tts_values, sizeof(Datum) * natts);
+  memcpy(dest->slot->tts_isnull, slot->tts_isnull, sizeof(bool) * natts);
+  ExecStoreVirtualTuple(dest->slot);
+
+  oldcxt = MemoryContextSwitchTo(dest->row_context);
+  result = shim_receive_next(&ctx);
+  MemoryContextSwitchTo(oldcxt);
+  MemoryContextReset(dest->row_context);
+  return result;
+}
+
+static void shim_startup(DestReceiver *self, int operation, TupleDesc typeinfo) {
+  ShimDestReceiver *dest = (ShimDestReceiver *)self;
+
+  dest->original->rStartup(dest->original, operation, typeinfo);
+  dest->slot = MakeSingleTupleTableSlot(typeinfo, &TTSOpsVirtual);
+  for (int i = 0; i < dest->nrewriters; i++) {
+    if (dest->rewriters[i].startup != NULL) {
+      dest->states[i] = dest->rewriters[i].startup(operation, typeinfo);
+    }
+  }
+}
+
+static void shim_shutdown(DestReceiver *self) {
+  ShimDestReceiver *dest = (ShimDestReceiver *)self;
+
+  for (int i = 0; i < dest->nrewriters; i++) {
+    if (dest->rewriters[i].shutdown != NULL) {
+      dest->rewriters[i].shutdown(dest->states[i]);
+    }
+  }
+  ExecDropSingleTupleTableSlot(dest->slot);
+  dest->slot = NULL;
+  dest->original->rShutdown(dest->original);
+}
+
+static void shim_destroy(DestReceiver *self) {
+  ShimDestReceiver *dest = (ShimDestReceiver *)self;
+
+  for (int i = 0; i < dest->nrewriters; i++) {
+    if (dest->rewriters[i].destroy != NULL) {
+      dest->rewriters[i].destroy(dest->states[i]);
+    }
+  }
+  dest->original->rDestroy(dest->original);
+}
+
+/* Wraps the destination of a top-level query, if any rewriter wants it. */
+static void shim_wrap_dest(QueryDesc *query_desc) {
+  MemoryContext oldcxt = MemoryContextSwitchTo(query_desc->estate->es_query_cxt);
+  ShimDestReceiver *dest = palloc0(sizeof(ShimDestReceiver));
+  ListCell *lc;
+
+  dest->rewriters = palloc(sizeof(OutputRewriter) * list_length(shim_rewriters));
+  foreach (lc, shim_rewriters) {
+    OutputRewriter *rewriter = (OutputRewriter *)lfirst(lc);
+
+    if (rewriter->filter == NULL || rewriter->filter(query_desc)) {
+      dest->rewriters[dest->nrewriters++] = *rewriter;
+    }
+  }
+
+  if (dest->nrewriters > 0) {
+    dest->states = palloc0(sizeof(void *) * dest->nrewriters);
+    dest->row_context =
+        AllocSetContextCreate(CurrentMemoryContext, "pgext output rewriter row", ALLOCSET_DEFAULT_SIZES);
+    dest->original = query_desc->dest;
+    dest->pub.receiveSlot = shim_receive_slot;
+    dest->pub.rStartup = shim_startup;
+    dest->pub.rShutdown = shim_shutdown;
+    dest->pub.rDestroy = shim_destroy;
+    dest->pub.mydest = PGEXT_SHIM_DEST;
+    query_desc->dest = &dest->pub;
+  }
+  MemoryContextSwitchTo(oldcxt);
+}
+
+static void shim_ExecutorRun(QueryDesc *query_desc, ScanDirection direction, uint64 count, bool execute_once) {
+  if (shim_executor_run_depth == 0) {
+    shim_wrap_dest(query_desc);
+  }
+  shim_executor_run_depth++;
+  PG_TRY();
+  {
+    if (prev_ExecutorRun) {
+      prev_ExecutorRun(query_desc, direction, count, execute_once);
+    } else {
+      standard_ExecutorRun(query_desc, direction, count, execute_once);
+    }
+  }
+  PG_FINALLY();
+  {
+    shim_executor_run_depth--;
+  }
+  PG_END_TRY();
+}
+
+static void shim_register_output_rewriter(const PgExtApi *api, const OutputRewriter *rewriter) {
+  MemoryContext oldcxt = MemoryContextSwitchTo(TopMemoryContext);
+  OutputRewriter *copy = palloc(sizeof(OutputRewriter));
+
+  *copy = *rewriter;
+  shim_rewriters = lappend(shim_rewriters, copy);
+  MemoryContextSwitchTo(oldcxt);
+}
+
+static Form_pg_attribute shim_slot_attr(TupleTableSlot *slot, int index) {
+  if (index < 0 || index >= slot->tts_tupleDescriptor->natts) {
+    elog(ERROR, "output rewriter slot has no attribute %d", index);
+  }
+  return TupleDescAttr(slot->tts_tupleDescriptor, index);
+}
+
+static void shim_slot_check_type(TupleTableSlot *slot, int index, Oid typid) {
+  Oid atttypid = shim_slot_attr(slot, index)->atttypid;
+
+  if (atttypid != typid) {
+    elog(ERROR,
+         "output rewriter slot attribute %d has type %s, not %s",
+         index,
+         format_type_be(atttypid),
+         format_type_be(typid));
+  }
+}
+
+static int shim_slot_attr_index(const PgExtApi *api, TupleTableSlot *slot, const char *name) {
+  for (int i = 0; i < slot->tts_tupleDescriptor->natts; i++) {
+    Form_pg_attribute attr = TupleDescAttr(slot->tts_tupleDescriptor, i);
+
+    if (!attr->attisdropped && strcmp(NameStr(attr->attname), name) == 0) {
+      return i;
+    }
+  }
+  return -1;
+}
+
+static void shim_slot_get_attr(
+    const PgExtApi *api, TupleTableSlot *slot, int index, Oid typid, Datum *value, bool *isnull) {
+  shim_slot_check_type(slot, index, typid);
+  *value = slot->tts_values[index];
+  *isnull = slot->tts_isnull[index];
+}
+
+static void shim_slot_set_attr(
+    const PgExtApi *api, TupleTableSlot *slot, int index, Oid typid, Datum value, bool isnull) {
+  shim_slot_check_type(slot, index, typid);
+  if (!isnull) {
+    int16 typlen;
+    bool typbyval;
+
+    get_typlenbyval(typid, &typlen, &typbyval);
+    value = datumCopy(value, typbyval, typlen);
+  }
+  slot->tts_values[index] = value;
+  slot->tts_isnull[index] = isnull;
+}
+
+/* Annotations, dependencies and conflicts */
+
+static void shim_set_annotation(const PgExtApi *api, const char *key, Oid typid, Datum value, bool isnull) {}
+
+static bool shim_get_annotation(const PgExtApi *api,
+                                const char *plugin,
+                                const char *key,
+                                Oid typid,
+                                Datum *value,
+                                bool *isnull) {
+  return false;
+}
+
+static void shim_add_dependency(const PgExtApi *api, const char *plugin) {}
+
+static void shim_add_conflict(const PgExtApi *api, const char *plugin) {}
+
+/* GUCs */
+
+static void shim_define_bool_guc(const PgExtApi *api,
+                                 const char *name,
+                                 const char *short_desc,
+                                 const char *long_desc,
+                                 bool *value,
+                                 bool boot_value,
+                                 GucContext context,
+                                 int flags) {
+  DefineCustomBoolVariable(
+      shim_full_name(name), short_desc, long_desc, value, boot_value, context, flags, NULL, NULL, NULL);
+  shim_defined_gucs = true;
+}
+
+static void shim_define_int_guc(const PgExtApi *api,
+                                const char *name,
+                                const char *short_desc,
+                                const char *long_desc,
+                                int *value,
+                                int boot_value,
+                                int min_value,
+                                int max_value,
+                                GucContext context,
+                                int flags) {
+  DefineCustomIntVariable(shim_full_name(name),
+                          short_desc,
+                          long_desc,
+                          value,
+                          boot_value,
+                          min_value,
+                          max_value,
+                          context,
+                          flags,
+                          NULL,
+                          NULL,
+                          NULL);
+  shim_defined_gucs = true;
+}
+
+static void shim_define_string_guc(const PgExtApi *api,
+                                   const char *name,
+                                   const char *short_desc,
+                                   const char *long_desc,
+                                   char **value,
+                                   const char *boot_value,
+                                   GucContext context,
+                                   int flags) {
+  DefineCustomStringVariable(
+      shim_full_name(name), short_desc, long_desc, value, boot_value, context, flags, NULL, NULL, NULL);
+  shim_defined_gucs = true;
+}
+
+static void shim_define_enum_guc(const PgExtApi *api,
+                                 const char *name,
+                                 const char *short_desc,
+                                 const char *long_desc,
+                                 int *value,
+                                 int boot_value,
+                                 const struct config_enum_entry *options,
+                                 GucContext context,
+                                 int flags) {
+  DefineCustomEnumVariable(
+      shim_full_name(name), short_desc, long_desc, value, boot_value, options, context, flags, NULL, NULL, NULL);
+  shim_defined_gucs = true;
+}
+
+/* PL/pgSQL */
+
+static void shim_register_plpgsql_plugin(const PgExtApi *api, struct PLpgSQL_plugin *plugin) {
+  struct PLpgSQL_plugin **var = (struct PLpgSQL_plugin **)find_rendezvous_variable("PLpgSQL_plugin");
+
+  *var = plugin;
+}
+
+/* emit_log_hook */
+
+static bool shim_log_hook_wants(ShimLogHook *hook, ErrorData *edata) {
+  if (edata->elevel < hook->min_elevel) {
+    return false;
+  }
+  if (hook->nsqlstates == 0) {
+    return true;
+  }
+  for (int i = 0; i < hook->nsqlstates; i++) {
+    if (hook->sqlstates[i] == edata->sqlerrcode) {
+      return true;
+    }
+  }
+  return false;
+}
+
+static void shim_emit_log_hook(ErrorData *edata) {
+  ListCell *lc;
+
+  if (prev_emit_log_hook) {
+    prev_emit_log_hook(edata);
+  }
+  foreach (lc, shim_log_hooks) {
+    ShimLogHook *hook = (ShimLogHook *)lfirst(lc);
+
+    if (shim_log_hook_wants(hook, edata)) {
+      hook->hook(edata);
+    }
+  }
+}
+
+static void shim_register_emit_log_hook(
+    const PgExtApi *api, emit_log_hook_type hook, int min_elevel, const int *sqlstates, int nsqlstates) {
+  MemoryContext oldcxt = MemoryContextSwitchTo(TopMemoryContext);
+  ShimLogHook *entry = palloc0(sizeof(ShimLogHook));
+
+  entry->hook = hook;
+  entry->min_elevel = min_elevel;
+  if (nsqlstates > 0) {
+    entry->sqlstates = palloc(sizeof(int) * nsqlstates);
+    memcpy(entry->sqlstates, sqlstates, sizeof(int) * nsqlstates);
+    entry->nsqlstates = nsqlstates;
+  }
+  shim_log_hooks = lappend(shim_log_hooks, entry);
+  MemoryContextSwitchTo(oldcxt);
+}
+
+/* Shared memory and LWLocks */
+
+static void shim_check_requests_allowed(void) {
+  if (!process_shared_preload_libraries_in_progress) {
+    elog(ERROR, "shared memory can only be requested from _PG_init of a preloaded plugin");
+  }
+}
+
+static void shim_reserve(void) {
+  ListCell *lc;
+
+  foreach (lc, shim_shmem_segments) {
+    RequestAddinShmemSpace(((ShimShmemSegment *)lfirst(lc))->size);
+  }
+  foreach (lc, shim_tranches) {
+    ShimTranche *tranche = (ShimTranche *)lfirst(lc);
+
+    RequestNamedLWLockTranche(tranche->name, tranche->num_locks);
+  }
+}
+
+#if PG_VERSION_NUM >= 150000
+static void shim_shmem_request_hook(void) {
+  if (prev_shmem_request_hook) {
+    prev_shmem_request_hook();
+  }
+  shim_reserve();
+}
+#endif
+
+static void shim_shmem_startup_hook(void) {
+  ListCell *lc;
+
+  if (prev_shmem_startup_hook) {
+    prev_shmem_startup_hook();
+  }
+  LWLockAcquire(AddinShmemInitLock, LW_EXCLUSIVE);
+  foreach (lc, shim_shmem_segments) {
+    ShimShmemSegment *segment = (ShimShmemSegment *)lfirst(lc);
+    bool found;
+    void *ptr = ShmemInitStruct(segment->name, segment->size, &found);
+
+    if (segment->startup) {
+      segment->startup(ptr, found);
+    }
+  }
+  LWLockRelease(AddinShmemInitLock);
+}
+
+static void shim_request_shmem(const PgExtApi *api, const char *name, size_t size, ShmemStartup startup) {
+  MemoryContext oldcxt;
+  ShimShmemSegment *segment;
+
+  shim_check_requests_allowed();
+  oldcxt = MemoryContextSwitchTo(TopMemoryContext);
+  segment = palloc(sizeof(ShimShmemSegment));
+  segment->name = shim_full_name(name);
+  segment->size = size;
+  segment->startup = startup;
+  shim_shmem_segments = lappend(shim_shmem_segments, segment);
+  MemoryContextSwitchTo(oldcxt);
+}
+
+static void shim_request_lwlocks(const PgExtApi *api, const char *tranche, int num_locks) {
+  MemoryContext oldcxt;
+  ShimTranche *entry;
+
+  shim_check_requests_allowed();
+  oldcxt = MemoryContextSwitchTo(TopMemoryContext);
+  entry = palloc(sizeof(ShimTranche));
+  entry->name = shim_full_name(tranche);
+  entry->num_locks = num_locks;
+  shim_tranches = lappend(shim_tranches, entry);
+  MemoryContextSwitchTo(oldcxt);
+}
+
+static union LWLockPadded *shim_get_lwlocks(const PgExtApi *api, const char *tranche) {
+  return GetNamedLWLockTranche(shim_full_name(tranche));
+}
+
+/* EXPLAIN */
+
+static void shim_register_explain_callback(const PgExtApi *api, ExplainCallback callback) {}
+
+/* Transaction callbacks */
+
+static void shim_register_xact_callback(const PgExtApi *api, XactCallback callback, void *arg) {
+  RegisterXactCallback(callback, arg);
+}
+
+static void shim_register_subxact_callback(const PgExtApi *api, SubXactCallback callback, void *arg) {
+  RegisterSubXactCallback(callback, arg);
+}
+
+/* Background workers */
+
+static void shim_register_bgworker(const PgExtApi *api, struct BackgroundWorker *worker) {
+  RegisterBackgroundWorker(worker);
+}
+
+static struct BackgroundWorkerHandle *shim_register_dynamic_bgworker(const PgExtApi *api,
+                                                                     struct BackgroundWorker *worker) {
+  BackgroundWorkerHandle *handle;
+
+  if (!RegisterDynamicBackgroundWorker(worker, &handle)) {
+    return NULL;
+  }
+  return handle;
+}
+
+/* Custom scans */
+
+static void shim_register_custom_scan(const PgExtApi *api, const struct CustomPathMethods *methods) {}
+
+/* Query id */
+
+static uint64 shim_query_id(const PgExtApi *api) {
+#if PG_VERSION_NUM >= 140000
+  return pgstat_get_my_query_id();
+#else
+  return 0;
+#endif
+}
+
+static const char *shim_normalized_query(const PgExtApi *api) { return NULL; }
+
+static PgExtApi shim_api = {
+    .plugin = NULL,
+    .register_output_rewriter = shim_register_output_rewriter,
+    .set_annotation = shim_set_annotation,
+    .get_annotation = shim_get_annotation,
+    .add_dependency = shim_add_dependency,
+    .add_conflict = shim_add_conflict,
+    .define_bool_guc = shim_define_bool_guc,
+    .define_int_guc = shim_define_int_guc,
+    .define_string_guc = shim_define_string_guc,
+    .define_enum_guc = shim_define_enum_guc,
+    .register_plpgsql_plugin = shim_register_plpgsql_plugin,
+    .register_emit_log_hook = shim_register_emit_log_hook,
+    .request_shmem = shim_request_shmem,
+    .request_lwlocks = shim_request_lwlocks,
+    .get_lwlocks = shim_get_lwlocks,
+    .register_explain_callback = shim_register_explain_callback,
+    .register_xact_callback = shim_register_xact_callback,
+    .register_subxact_callback = shim_register_subxact_callback,
+    .register_bgworker = shim_register_bgworker,
+    .register_dynamic_bgworker = shim_register_dynamic_bgworker,
+    .register_custom_scan = shim_register_custom_scan,
+    .slot_attr_index = shim_slot_attr_index,
+    .slot_get_attr = shim_slot_get_attr,
+    .slot_set_attr = shim_slot_set_attr,
+    .query_id = shim_query_id,
+    .normalized_query = shim_normalized_query,
+};
+
+__attribute__((visibility("hidden"))) struct PgExtApi *pgext_before_init(const char *name) {
+  if (__pgext_before_init != NULL) {
+    return __pgext_before_init(name);
+  }
+  shim_plugin = MemoryContextStrdup(TopMemoryContext, name);
+  return &shim_api;
+}
+
+__attribute__((visibility("hidden"))) void pgext_after_init(void) {
+  static bool installed = false;
+
+  if (__pgext_after_init != NULL) {
+    __pgext_after_init();
+    return;
+  }
+
+  /* the hooks would end up calling themselves as the previous hook */
+  if (installed) {
+    return;
+  }
+  installed = true;
+
+  if (shim_rewriters != NIL) {
+    prev_ExecutorRun = ExecutorRun_hook;
+    ExecutorRun_hook = shim_ExecutorRun;
+  }
+
+  if (shim_log_hooks != NIL) {
+    prev_emit_log_hook = emit_log_hook;
+    emit_log_hook = shim_emit_log_hook;
+  }
+
+  if (shim_shmem_segments != NIL || shim_tranches != NIL) {
+#if PG_VERSION_NUM >= 150000
+    prev_shmem_request_hook = shmem_request_hook;
+    shmem_request_hook = shim_shmem_request_hook;
+#else
+    shim_reserve();
+#endif
+    prev_shmem_startup_hook = shmem_startup_hook;
+    shmem_startup_hook = shim_shmem_startup_hook;
+  }
+
+  if (shim_defined_gucs) {
+#if PG_VERSION_NUM >= 150000
+    MarkGUCPrefixReserved(shim_plugin);
+#else
+    EmitWarningsOnPlaceholders(shim_plugin);
+#endif
+  }
+}
@@ -4,6 +4,16 @@
 #include <stdint.h>
 #include <stdlib.h>
 
+#include "access/xact.h"
+#include "executor/execdesc.h"
+#include "utils/guc.h"
+
+struct BackgroundWorker;
+struct BackgroundWorkerHandle;
+struct CustomPathMethods;
+struct ExplainState;
+struct PLpgSQL_plugin;
+union LWLockPadded;
 
 typedef struct String String;
 
@@ -17,6 +27,10 @@ typedef void (*OutputRewriterDestroy)(void*);
 
 typedef bool (*OutputRewriterReceiveSlot)(void*, TupleTableSlot *slot, void*, bool(*)(void*));
 
+typedef void (*ShmemStartup)(void *segment, bool found);
+
+typedef void (*ExplainCallback)(QueryDesc *query_desc, struct ExplainState *es);
+
 typedef struct OutputRewriter {
   OutputRewriterFilter filter;
   OutputRewriterStartup startup;
@@ -28,8 +42,93 @@ typedef struct OutputRewriter {
 typedef struct PgExtApi {
   const struct String *plugin;
   void (*register_output_rewriter)(const struct PgExtApi *api, const struct OutputRewriter *rewriter);
+  void (*set_annotation)(const struct PgExtApi *api, const char *key, Oid typid, Datum value, bool isnull);
+  bool (*get_annotation)(const struct PgExtApi *api,
+                         const char *plugin,
+                         const char *key,
+                         Oid typid,
+                         Datum *value,
+                         bool *isnull);
+  void (*add_dependency)(const struct PgExtApi *api, const char *plugin);
+  void (*add_conflict)(const struct PgExtApi *api, const char *plugin);
+  void (*define_bool_guc)(const struct PgExtApi *api,
+                          const char *name,
+                          const char *short_desc,
+                          const char *long_desc,
+                          bool *value,
+                          bool boot_value,
+                          GucContext context,
+                          int flags);
+  void (*define_int_guc)(const struct PgExtApi *api,
+                         const char *name,
+                         const char *short_desc,
+                         const char *long_desc,
+                         int *value,
+                         int boot_value,
+                         int min_value,
+                         int max_value,
+                         GucContext context,
+                         int flags);
+  void (*define_string_guc)(const struct PgExtApi *api,
+                            const char *name,
+                            const char *short_desc,
+                            const char *long_desc,
+                            char **value,
+                            const char *boot_value,
+                            GucContext context,
+                            int flags);
+  void (*define_enum_guc)(const struct PgExtApi *api,
+                          const char *name,
+                          const char *short_desc,
+                          const char *long_desc,
+                          int *value,
+                          int boot_value,
+                          const struct config_enum_entry *options,
+                          GucContext context,
+                          int flags);
+  void (*register_plpgsql_plugin)(const struct PgExtApi *api, struct PLpgSQL_plugin *plugin);
+  void (*register_emit_log_hook)(const struct PgExtApi *api,
+                                 emit_log_hook_type hook,
+                                 int min_elevel,
+                                 const int *sqlstates,
+                                 int nsqlstates);
+  void (*request_shmem)(const struct PgExtApi *api, const char *name, size_t size, ShmemStartup startup);
+  void (*request_lwlocks)(const struct PgExtApi *api, const char *tranche, int num_locks);
+  union LWLockPadded *(*get_lwlocks)(const struct PgExtApi *api, const char *tranche);
+  void (*register_explain_callback)(const struct PgExtApi *api, ExplainCallback callback);
+  void (*register_xact_callback)(const struct PgExtApi *api, XactCallback callback, void *arg);
+  void (*register_subxact_callback)(const struct PgExtApi *api, SubXactCallback callback, void *arg);
+  void (*register_bgworker)(const struct PgExtApi *api, struct BackgroundWorker *worker);
+  struct BackgroundWorkerHandle *(*register_dynamic_bgworker)(const struct PgExtApi *api,
+                                                              struct BackgroundWorker *worker);
+  void (*register_custom_scan)(const struct PgExtApi *api, const struct CustomPathMethods *methods);
+  int (*slot_attr_index)(const struct PgExtApi *api, TupleTableSlot *slot, const char *name);
+  void (*slot_get_attr)(const struct PgExtApi *api,
+                        TupleTableSlot *slot,
+                        int index,
+                        Oid typid,
+                        Datum *value,
+                        bool *isnull);
+  void (*slot_set_attr)(const struct PgExtApi *api, TupleTableSlot *slot, int index, Oid typid, Datum value, bool isnull);
+  uint64 (*query_id)(const struct PgExtApi *api);
+  const char *(*normalized_query)(const struct PgExtApi *api);
 } PgExtApi;
 
-void __pgext_after_init(void);
+/*
+ * Provided by pgextmgr. Weak, so that a plugin still loads when pgextmgr has
+ * not been loaded before it; call them through pgext_before_init and
+ * pgext_after_init of pgext_shim.c, which check for that.
+ */
+__attribute__((weak)) void __pgext_after_init(void);
+
+__attribute__((weak)) struct PgExtApi *__pgext_before_init(const char *name);
+
+/*
+ * Provided by pgext_shim.c: forward to pgextmgr if it is loaded, otherwise
+ * return an api that installs everything with the plain PostgreSQL hooks.
+ * Every plugin links its own copy of the shim, so they are hidden: a plugin
+ * must not end up calling the shim of another plugin loaded before it.
+ */
+__attribute__((visibility("hidden"))) struct PgExtApi *pgext_before_init(const char *name);
 
-struct PgExtApi *__pgext_before_init(const char *name);
+__attribute__((visibility("hidden"))) void pgext_after_init(void);
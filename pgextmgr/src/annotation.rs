@@ -0,0 +1,145 @@
+//! Per-query annotations shared between plugins
+//!
+//! A plugin attaches typed values under its own name (e.g. the hint text in a
+//! planner hook), and any other plugin can read them later in the same query
+//! (e.g. in an executor hook). The store belongs to the top-level statement:
+//! it is emptied when the next one is analyzed, and after the statement's
+//! `ExecutorEnd`. It does not depend on the protocol messages, so that with the
+//! extended protocol, the annotations set while planning in Bind are still
+//! there in Execute. Statements run while another one is planned or executed
+//! (e.g. by a function) share its store.
+
+use std::collections::BTreeMap;
+
+use pgrx::pg_sys::{self, AsPgCStr, Datum, Oid};
+use pgrx::prelude::*;
+use pgrx::PgMemoryContexts;
+
+use crate::hook_ext::{
+  EXECUTOR_END_HOOK_NESTED_DEPTH, EXECUTOR_FINISH_HOOK_NESTED_DEPTH, EXECUTOR_RUN_HOOK_NESTED_DEPTH,
+  EXECUTOR_START_HOOK_NESTED_DEPTH, PLANNER_HOOK_NESTED_DEPTH, POST_PARSE_ANALYZE_HOOK_NESTED_DEPTH,
+};
+
+struct Annotation {
+  typid: Oid,
+  value: Datum,
+  isnull: bool,
+}
+
+struct AnnotationStore {
+  context: pg_sys::MemoryContext,
+  annotations: BTreeMap<(String, String), Annotation>,
+}
+
+/// The store of the current statement, or null if no annotation has been
+/// set yet.
+static mut CURRENT_STORE: *mut AnnotationStore = std::ptr::null_mut();
+
+impl Drop for AnnotationStore {
+  fn drop(&mut self) {
+    unsafe {
+      if std::ptr::eq(CURRENT_STORE, self) {
+        CURRENT_STORE = std::ptr::null_mut();
+      }
+    }
+  }
+}
+
+/// Holds the store of the current statement, reset when it ends.
+static mut STATEMENT_CONTEXT: pg_sys::MemoryContext = std::ptr::null_mut();
+
+/// Whether the hook being called belongs to the top-level statement, i.e. no
+/// other statement is being analyzed, planned or executed.
+pub(crate) unsafe fn top_level() -> bool {
+  POST_PARSE_ANALYZE_HOOK_NESTED_DEPTH
+    + PLANNER_HOOK_NESTED_DEPTH
+    + EXECUTOR_START_HOOK_NESTED_DEPTH
+    + EXECUTOR_RUN_HOOK_NESTED_DEPTH
+    + EXECUTOR_FINISH_HOOK_NESTED_DEPTH
+    + EXECUTOR_END_HOOK_NESTED_DEPTH
+    == 1
+}
+
+/// Drop the annotations of the previous statement, called when a top-level
+/// statement is analyzed or has ended.
+pub(crate) unsafe fn reset() {
+  if !STATEMENT_CONTEXT.is_null() {
+    pg_sys::MemoryContextReset(STATEMENT_CONTEXT);
+  }
+}
+
+unsafe fn current_store() -> &'static mut AnnotationStore {
+  if STATEMENT_CONTEXT.is_null() {
+    STATEMENT_CONTEXT = PgMemoryContexts::TopMemoryContext.switch_to(|_| {
+      pg_sys::AllocSetContextCreateExtended(
+        pg_sys::TopMemoryContext,
+        "pgext annotations".as_pg_cstr(),
+        pg_sys::ALLOCSET_DEFAULT_MINSIZE as usize,
+        pg_sys::ALLOCSET_DEFAULT_INITSIZE as usize,
+        pg_sys::ALLOCSET_DEFAULT_MAXSIZE as usize,
+      )
+    });
+  }
+  if CURRENT_STORE.is_null() {
+    let context = STATEMENT_CONTEXT;
+    CURRENT_STORE = PgMemoryContexts::For(context).leak_and_drop_on_delete(AnnotationStore {
+      context,
+      annotations: BTreeMap::new(),
+    });
+  }
+  &mut *CURRENT_STORE
+}
+
+/// Copy a datum into the current memory context so that it outlives the caller.
+pub(crate) unsafe fn copy_datum(value: Datum, typid: Oid) -> Datum {
+  let mut typlen = 0;
+  let mut typbyval = false;
+  pg_sys::get_typlenbyval(typid, &mut typlen, &mut typbyval);
+  if typbyval {
+    value
+  } else if typlen == -1 {
+    Datum::from(pg_sys::pg_detoast_datum_copy(value.cast_mut_ptr()))
+  } else if typlen == -2 {
+    Datum::from(pg_sys::pstrdup(value.cast_mut_ptr()))
+  } else {
+    let copy = pg_sys::palloc(typlen as usize);
+    std::ptr::copy_nonoverlapping(value.cast_mut_ptr::<u8>(), copy as *mut u8, typlen as usize);
+    Datum::from(copy)
+  }
+}
+
+/// Attach an annotation of `plugin` to the current query, replacing any
+/// previous value of the same key.
+pub unsafe fn set_annotation(plugin: &str, key: &str, typid: Oid, value: Datum, isnull: bool) {
+  let store = current_store();
+  let value = if isnull {
+    value
+  } else {
+    PgMemoryContexts::For(store.context).switch_to(|_| copy_datum(value, typid))
+  };
+  store.annotations.insert(
+    (plugin.to_string(), key.to_string()),
+    Annotation { typid, value, isnull },
+  );
+}
+
+/// Read an annotation of `plugin` from the current query. Returns `None` if it
+/// has not been set, and raises an error if it was set with another type.
+pub unsafe fn get_annotation(plugin: &str, key: &str, typid: Oid) -> Option<(Datum, bool)> {
+  if CURRENT_STORE.is_null() {
+    return None;
+  }
+  let annotation = (*CURRENT_STORE)
+    .annotations
+    .get(&(plugin.to_string(), key.to_string()))?;
+  if annotation.typid != typid {
+    error!(
+      "annotation {}.{} has type {}, not {}",
+      plugin,
+      key,
+      std::ffi::CStr::from_ptr(pg_sys::format_type_be(annotation.typid)).to_string_lossy(),
+      std::ffi::CStr::from_ptr(pg_sys::format_type_be(typid)).to_string_lossy()
+    );
+  }
+  Some((annotation.value, annotation.isnull))
+}
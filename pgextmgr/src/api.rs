@@ -1,20 +1,25 @@
-use std::ffi::c_int;
+use std::ffi::{c_char, c_int, c_void, CStr};
 
-use pgrx::pg_sys::{QueryDesc, TupleDesc, TupleTableSlot};
+use pgrx::pg_sys::{
+  config_enum_entry, emit_log_hook_type, uint64, BackgroundWorker, BackgroundWorkerHandle, CustomPathMethods, Datum,
+  GucContext, LWLockPadded, Oid, PLpgSQL_plugin, QueryDesc, SubXactCallback, TupleDesc, TupleTableSlot, XactCallback,
+};
 
+use crate::dependency::{self, DependencyKind};
+use crate::emit_log::LogFilter;
+use crate::explain::ExplainCallback;
 use crate::hook_mgr::ALL_HOOKS;
+use crate::output_rewriter::{RewriterMode, RewriterSlot};
+use crate::shmem::ShmemStartup;
+use crate::xact::CallbackArg;
+use crate::{annotation, bgworker, custom_scan, guc, plpgsql, query_id, shmem};
 
 pub type OutputRewriterFilter = Option<extern "C" fn(query_desc: *mut QueryDesc) -> bool>;
-pub type OutputRewriterStartup = Option<extern "C" fn(operation: c_int, type_info: TupleDesc) -> *mut std::ffi::c_void>;
-pub type OutputRewriterShutdown = Option<extern "C" fn(*mut std::ffi::c_void)>;
-pub type OutputRewriterDestroy = Option<extern "C" fn(*mut std::ffi::c_void)>;
+pub type OutputRewriterStartup = Option<extern "C" fn(operation: c_int, type_info: TupleDesc) -> *mut c_void>;
+pub type OutputRewriterShutdown = Option<extern "C" fn(*mut c_void)>;
+pub type OutputRewriterDestroy = Option<extern "C" fn(*mut c_void)>;
 pub type OutputRewriterReceiveSlot = Option<
-  extern "C" fn(
-    *mut std::ffi::c_void,
-    slot: *mut TupleTableSlot,
-    *mut std::ffi::c_void,
-    unsafe extern "C" fn(*mut std::ffi::c_void) -> bool,
-  ) -> bool,
+  extern "C" fn(*mut c_void, slot: *mut TupleTableSlot, *mut c_void, unsafe extern "C" fn(*mut c_void) -> bool) -> bool,
 >;
 
 #[repr(C)]
@@ -29,8 +34,109 @@ pub struct OutputRewriter {
 
 #[repr(C)]
 pub struct PgExtApi {
-  plugin: *const String,
-  register_output_rewriter: unsafe extern "C" fn(api: &PgExtApi, rewriter: &OutputRewriter),
+  pub(crate) plugin: *const String,
+  pub(crate) register_output_rewriter: unsafe extern "C" fn(api: &PgExtApi, rewriter: &OutputRewriter),
+  pub(crate) set_annotation:
+    unsafe extern "C" fn(api: &PgExtApi, key: *const c_char, typid: Oid, value: Datum, isnull: bool),
+  pub(crate) get_annotation: unsafe extern "C" fn(
+    api: &PgExtApi,
+    plugin: *const c_char,
+    key: *const c_char,
+    typid: Oid,
+    value: *mut Datum,
+    isnull: *mut bool,
+  ) -> bool,
+  pub(crate) add_dependency: unsafe extern "C" fn(api: &PgExtApi, plugin: *const c_char),
+  pub(crate) add_conflict: unsafe extern "C" fn(api: &PgExtApi, plugin: *const c_char),
+  pub(crate) define_bool_guc: unsafe extern "C" fn(
+    api: &PgExtApi,
+    name: *const c_char,
+    short_desc: *const c_char,
+    long_desc: *const c_char,
+    value: *mut bool,
+    boot_value: bool,
+    context: GucContext,
+    flags: c_int,
+  ),
+  pub(crate) define_int_guc: unsafe extern "C" fn(
+    api: &PgExtApi,
+    name: *const c_char,
+    short_desc: *const c_char,
+    long_desc: *const c_char,
+    value: *mut c_int,
+    boot_value: c_int,
+    min_value: c_int,
+    max_value: c_int,
+    context: GucContext,
+    flags: c_int,
+  ),
+  pub(crate) define_string_guc: unsafe extern "C" fn(
+    api: &PgExtApi,
+    name: *const c_char,
+    short_desc: *const c_char,
+    long_desc: *const c_char,
+    value: *mut *mut c_char,
+    boot_value: *const c_char,
+    context: GucContext,
+    flags: c_int,
+  ),
+  pub(crate) define_enum_guc: unsafe extern "C" fn(
+    api: &PgExtApi,
+    name: *const c_char,
+    short_desc: *const c_char,
+    long_desc: *const c_char,
+    value: *mut c_int,
+    boot_value: c_int,
+    options: *const config_enum_entry,
+    context: GucContext,
+    flags: c_int,
+  ),
+  pub(crate) register_plpgsql_plugin: unsafe extern "C" fn(api: &PgExtApi, plugin: *mut PLpgSQL_plugin),
+  pub(crate) register_emit_log_hook: unsafe extern "C" fn(
+    api: &PgExtApi,
+    hook: emit_log_hook_type,
+    min_elevel: c_int,
+    sqlstates: *const c_int,
+    nsqlstates: c_int,
+  ),
+  pub(crate) request_shmem:
+    unsafe extern "C" fn(api: &PgExtApi, name: *const c_char, size: usize, startup: ShmemStartup),
+  pub(crate) request_lwlocks: unsafe extern "C" fn(api: &PgExtApi, tranche: *const c_char, num_locks: c_int),
+  pub(crate) get_lwlocks: unsafe extern "C" fn(api: &PgExtApi, tranche: *const c_char) -> *mut LWLockPadded,
+  pub(crate) register_explain_callback: unsafe extern "C" fn(api: &PgExtApi, callback: ExplainCallback),
+  pub(crate) register_xact_callback: unsafe extern "C" fn(api: &PgExtApi, callback: XactCallback, arg: *mut c_void),
+  pub(crate) register_subxact_callback:
+    unsafe extern "C" fn(api: &PgExtApi, callback: SubXactCallback, arg: *mut c_void),
+  pub(crate) register_bgworker: unsafe extern "C" fn(api: &PgExtApi, worker: *mut BackgroundWorker),
+  pub(crate) register_dynamic_bgworker:
+    unsafe extern "C" fn(api: &PgExtApi, worker: *mut BackgroundWorker) -> *mut BackgroundWorkerHandle,
+  pub(crate) register_custom_scan: unsafe extern "C" fn(api: &PgExtApi, methods: *const CustomPathMethods),
+  pub(crate) slot_attr_index:
+    unsafe extern "C" fn(api: &PgExtApi, slot: *mut TupleTableSlot, name: *const c_char) -> c_int,
+  pub(crate) slot_get_attr: unsafe extern "C" fn(
+    api: &PgExtApi,
+    slot: *mut TupleTableSlot,
+    index: c_int,
+    typid: Oid,
+    value: *mut Datum,
+    isnull: *mut bool,
+  ),
+  pub(crate) slot_set_attr: unsafe extern "C" fn(
+    api: &PgExtApi,
+    slot: *mut TupleTableSlot,
+    index: c_int,
+    typid: Oid,
+    value: Datum,
+    isnull: bool,
+  ),
+  pub(crate) query_id: unsafe extern "C" fn(api: &PgExtApi) -> uint64,
+  pub(crate) normalized_query: unsafe extern "C" fn(api: &PgExtApi) -> *const c_char,
+}
+
+impl Drop for PgExtApi {
+  fn drop(&mut self) {
+    unsafe { drop(Box::from_raw(self.plugin as *mut String)) }
+  }
 }
 
 impl PgExtApi {
@@ -38,12 +144,328 @@ impl PgExtApi {
     PgExtApi {
       plugin: Box::leak(Box::new(plugin)),
       register_output_rewriter: Self::register_output_rewriter,
+      set_annotation: Self::set_annotation,
+      get_annotation: Self::get_annotation,
+      add_dependency: Self::add_dependency,
+      add_conflict: Self::add_conflict,
+      define_bool_guc: Self::define_bool_guc,
+      define_int_guc: Self::define_int_guc,
+      define_string_guc: Self::define_string_guc,
+      define_enum_guc: Self::define_enum_guc,
+      register_plpgsql_plugin: Self::register_plpgsql_plugin,
+      register_emit_log_hook: Self::register_emit_log_hook,
+      request_shmem: Self::request_shmem,
+      request_lwlocks: Self::request_lwlocks,
+      get_lwlocks: Self::get_lwlocks,
+      register_explain_callback: Self::register_explain_callback,
+      register_xact_callback: Self::register_xact_callback,
+      register_subxact_callback: Self::register_subxact_callback,
+      register_bgworker: Self::register_bgworker,
+      register_dynamic_bgworker: Self::register_dynamic_bgworker,
+      register_custom_scan: Self::register_custom_scan,
+      slot_attr_index: Self::slot_attr_index,
+      slot_get_attr: Self::slot_get_attr,
+      slot_set_attr: Self::slot_set_attr,
+      query_id: Self::query_id,
+      normalized_query: Self::normalized_query,
     }
   }
 
   unsafe extern "C" fn register_output_rewriter(api: &PgExtApi, rewriter: &OutputRewriter) {
     ALL_HOOKS
       .rewriters
-      .push(((*api.plugin).clone(), rewriter.clone(), true));
+      .push(((*api.plugin).clone(), rewriter.clone(), true, RewriterMode::Active));
+  }
+
+  /// Attach a value to the current query under the plugin's name.
+  unsafe extern "C" fn set_annotation(api: &PgExtApi, key: *const c_char, typid: Oid, value: Datum, isnull: bool) {
+    annotation::set_annotation(
+      &*api.plugin,
+      &CStr::from_ptr(key).to_string_lossy(),
+      typid,
+      value,
+      isnull,
+    );
+  }
+
+  /// Read a value attached to the current query by `plugin`. Returns false if
+  /// there is no such annotation.
+  unsafe extern "C" fn get_annotation(
+    _api: &PgExtApi,
+    plugin: *const c_char,
+    key: *const c_char,
+    typid: Oid,
+    value: *mut Datum,
+    isnull: *mut bool,
+  ) -> bool {
+    let plugin = CStr::from_ptr(plugin).to_string_lossy();
+    let key = CStr::from_ptr(key).to_string_lossy();
+    if let Some((v, n)) = annotation::get_annotation(&plugin, &key, typid) {
+      *value = v;
+      *isnull = n;
+      true
+    } else {
+      false
+    }
+  }
+
+  /// Declare that `plugin` must be loaded before this plugin.
+  unsafe extern "C" fn add_dependency(api: &PgExtApi, plugin: *const c_char) {
+    dependency::add_dependency(
+      &*api.plugin,
+      DependencyKind::Requires,
+      &CStr::from_ptr(plugin).to_string_lossy(),
+    );
+  }
+
+  /// Declare that `plugin` cannot be loaded together with this plugin.
+  unsafe extern "C" fn add_conflict(api: &PgExtApi, plugin: *const c_char) {
+    dependency::add_dependency(
+      &*api.plugin,
+      DependencyKind::ConflictsWith,
+      &CStr::from_ptr(plugin).to_string_lossy(),
+    );
+  }
+
+  /// Define `<plugin>.<name>` as a boolean setting.
+  #[allow(clippy::too_many_arguments)]
+  unsafe extern "C" fn define_bool_guc(
+    api: &PgExtApi,
+    name: *const c_char,
+    short_desc: *const c_char,
+    long_desc: *const c_char,
+    value: *mut bool,
+    boot_value: bool,
+    context: GucContext,
+    flags: c_int,
+  ) {
+    guc::define_bool(
+      &*api.plugin,
+      name,
+      short_desc,
+      long_desc,
+      value,
+      boot_value,
+      context,
+      flags,
+    );
+  }
+
+  /// Define `<plugin>.<name>` as an integer setting.
+  #[allow(clippy::too_many_arguments)]
+  unsafe extern "C" fn define_int_guc(
+    api: &PgExtApi,
+    name: *const c_char,
+    short_desc: *const c_char,
+    long_desc: *const c_char,
+    value: *mut c_int,
+    boot_value: c_int,
+    min_value: c_int,
+    max_value: c_int,
+    context: GucContext,
+    flags: c_int,
+  ) {
+    guc::define_int(
+      &*api.plugin,
+      name,
+      short_desc,
+      long_desc,
+      value,
+      boot_value,
+      min_value,
+      max_value,
+      context,
+      flags,
+    );
+  }
+
+  /// Define `<plugin>.<name>` as a string setting.
+  #[allow(clippy::too_many_arguments)]
+  unsafe extern "C" fn define_string_guc(
+    api: &PgExtApi,
+    name: *const c_char,
+    short_desc: *const c_char,
+    long_desc: *const c_char,
+    value: *mut *mut c_char,
+    boot_value: *const c_char,
+    context: GucContext,
+    flags: c_int,
+  ) {
+    guc::define_string(
+      &*api.plugin,
+      name,
+      short_desc,
+      long_desc,
+      value,
+      boot_value,
+      context,
+      flags,
+    );
+  }
+
+  /// Define `<plugin>.<name>` as an enum setting.
+  #[allow(clippy::too_many_arguments)]
+  unsafe extern "C" fn define_enum_guc(
+    api: &PgExtApi,
+    name: *const c_char,
+    short_desc: *const c_char,
+    long_desc: *const c_char,
+    value: *mut c_int,
+    boot_value: c_int,
+    options: *const config_enum_entry,
+    context: GucContext,
+    flags: c_int,
+  ) {
+    guc::define_enum(
+      &*api.plugin,
+      name,
+      short_desc,
+      long_desc,
+      value,
+      boot_value,
+      options,
+      context,
+      flags,
+    );
+  }
+
+  /// Add a PL/pgSQL instrumentation plugin. Its callbacks are called after the
+  /// ones of plugins loaded earlier, and the helper functions are filled in by
+  /// pgextmgr before `func_setup`.
+  unsafe extern "C" fn register_plpgsql_plugin(api: &PgExtApi, plugin: *mut PLpgSQL_plugin) {
+    plpgsql::register(&*api.plugin, plugin);
+  }
+
+  /// Add an `emit_log_hook` that is only called for messages of at least
+  /// `min_elevel`, and only for the given SQLSTATEs if `nsqlstates` is not 0.
+  unsafe extern "C" fn register_emit_log_hook(
+    api: &PgExtApi,
+    hook: emit_log_hook_type,
+    min_elevel: c_int,
+    sqlstates: *const c_int,
+    nsqlstates: c_int,
+  ) {
+    let sqlstates = if nsqlstates > 0 {
+      std::slice::from_raw_parts(sqlstates, nsqlstates as usize)
+    } else {
+      &[]
+    };
+    ALL_HOOKS
+      .emit_log_hook
+      .register((*api.plugin).clone(), hook, LogFilter::new(min_elevel, sqlstates));
+  }
+
+  /// Reserve a shared memory segment `<plugin>.<name>`. `startup` gets the
+  /// segment from `shmem_startup_hook`. Only possible from `_PG_init`.
+  unsafe extern "C" fn request_shmem(api: &PgExtApi, name: *const c_char, size: usize, startup: ShmemStartup) {
+    shmem::request_shmem(&*api.plugin, name, size, startup);
+  }
+
+  /// Reserve a tranche `<plugin>.<tranche>` of LWLocks. Only possible from
+  /// `_PG_init`.
+  unsafe extern "C" fn request_lwlocks(api: &PgExtApi, tranche: *const c_char, num_locks: c_int) {
+    shmem::request_lwlocks(&*api.plugin, tranche, num_locks);
+  }
+
+  /// The LWLocks of a tranche reserved with `request_lwlocks`, available from
+  /// `shmem_startup_hook` on.
+  unsafe extern "C" fn get_lwlocks(api: &PgExtApi, tranche: *const c_char) -> *mut LWLockPadded {
+    shmem::get_lwlocks(&*api.plugin, tranche)
+  }
+
+  /// Add a callback that appends properties to EXPLAIN, under the plugin's own
+  /// group in the "pgext" section.
+  unsafe extern "C" fn register_explain_callback(api: &PgExtApi, callback: ExplainCallback) {
+    ALL_HOOKS
+      .explain_callbacks
+      .register((*api.plugin).clone(), callback, ());
+  }
+
+  /// Same as `RegisterXactCallback`, but only called while the plugin is
+  /// enabled.
+  unsafe extern "C" fn register_xact_callback(api: &PgExtApi, callback: XactCallback, arg: *mut c_void) {
+    ALL_HOOKS
+      .xact_callbacks
+      .register((*api.plugin).clone(), callback, CallbackArg(arg));
+  }
+
+  /// Same as `RegisterSubXactCallback`, but only called while the plugin is
+  /// enabled.
+  unsafe extern "C" fn register_subxact_callback(api: &PgExtApi, callback: SubXactCallback, arg: *mut c_void) {
+    ALL_HOOKS
+      .subxact_callbacks
+      .register((*api.plugin).clone(), callback, CallbackArg(arg));
+  }
+
+  /// Same as `RegisterBackgroundWorker`. Only possible from `_PG_init`.
+  unsafe extern "C" fn register_bgworker(api: &PgExtApi, worker: *mut BackgroundWorker) {
+    bgworker::register_static(&*api.plugin, worker);
+  }
+
+  /// Same as `RegisterDynamicBackgroundWorker`, but the worker is stopped while
+  /// the plugin is disabled. Returns null on failure or if the plugin is
+  /// disabled. The handle no longer refers to the worker once it has been
+  /// restarted. Not possible from the postmaster, i.e. from `_PG_init` of a
+  /// preloaded plugin.
+  unsafe extern "C" fn register_dynamic_bgworker(
+    api: &PgExtApi,
+    worker: *mut BackgroundWorker,
+  ) -> *mut BackgroundWorkerHandle {
+    bgworker::register_dynamic(&*api.plugin, worker)
+  }
+
+  /// Declare that the plugin provides the custom scan `methods->CustomName`,
+  /// so that its custom paths are dropped while the plugin is disabled. The
+  /// plugin adds the paths from `set_rel_pathlist_hook` as usual.
+  unsafe extern "C" fn register_custom_scan(api: &PgExtApi, methods: *const CustomPathMethods) {
+    custom_scan::register(&*api.plugin, methods);
+  }
+
+  /// Index of the attribute `name` in the slot passed to an output rewriter,
+  /// or -1 if there is none.
+  unsafe extern "C" fn slot_attr_index(_api: &PgExtApi, slot: *mut TupleTableSlot, name: *const c_char) -> c_int {
+    RewriterSlot::from_ptr(slot)
+      .attr_index(&CStr::from_ptr(name).to_string_lossy())
+      .map_or(-1, |i| i as c_int)
+  }
+
+  /// Read attribute `index` of the slot passed to an output rewriter. Raises
+  /// an error if it is not of type `typid`.
+  unsafe extern "C" fn slot_get_attr(
+    _api: &PgExtApi,
+    slot: *mut TupleTableSlot,
+    index: c_int,
+    typid: Oid,
+    value: *mut Datum,
+    isnull: *mut bool,
+  ) {
+    let slot = RewriterSlot::from_ptr(slot);
+    slot.check_type(index as usize, typid);
+    (*value, *isnull) = slot.get_datum(index as usize);
+  }
+
+  /// Replace attribute `index` of the slot passed to an output rewriter. The
+  /// value is copied, and freed after the row has been sent.
+  unsafe extern "C" fn slot_set_attr(
+    _api: &PgExtApi,
+    slot: *mut TupleTableSlot,
+    index: c_int,
+    typid: Oid,
+    value: Datum,
+    isnull: bool,
+  ) {
+    RewriterSlot::from_ptr(slot).set_datum(index as usize, typid, value, isnull);
+  }
+
+  /// The query id of the statement analyzed last, or 0 if none has been
+  /// computed. Available from the plugin's `post_parse_analyze_hook` on.
+  unsafe extern "C" fn query_id(_api: &PgExtApi) -> uint64 {
+    query_id::current()
+  }
+
+  /// The text of the statement analyzed last with its constants replaced by
+  /// `$n`, or null if there is none. Valid until the end of the query.
+  unsafe extern "C" fn normalized_query(_api: &PgExtApi) -> *const c_char {
+    query_id::normalized_query()
   }
 }
@@ -0,0 +1,84 @@
+//! `check_password_hook` and `ClientAuthentication_hook`
+//!
+//! Every plugin gets a vote. A plugin rejects by raising an error; instead of
+//! stopping at the first rejection, pgextmgr calls the remaining plugins as
+//! well and reports all rejection reasons in a single error. Within a
+//! transaction, each plugin is called in its own subtransaction, so that one
+//! that raised an error leaves nothing behind for the next. As with the other
+//! security hooks, plugins are called whether they are enabled or not.
+//!
+//! A hook installed before pgextmgr, e.g. by a library loaded earlier, is
+//! called first, outside the vote: its error is raised as is, and the plugins
+//! are only called if it accepts.
+
+use std::ffi::{c_char, c_int};
+
+use pgrx::pg_sys::panic::ErrorReport;
+use pgrx::pg_sys::{self, Datum, PasswordType, Port};
+use pgrx::prelude::*;
+
+use crate::callback;
+use crate::hook_mgr::ALL_HOOKS;
+
+pub type ClientAuthenticationHookType = Option<unsafe extern "C" fn(port: *mut Port, status: c_int)>;
+
+// not included in the pgrx bindings
+extern "C" {
+  pub static mut ClientAuthentication_hook: ClientAuthenticationHookType;
+}
+
+/// Call `f` for every plugin, and collect the errors they raise as
+/// "plugin: message".
+fn collect_rejections<T: Copy>(hooks: &[(String, Option<T>, ())], f: impl Fn(T)) -> Vec<String> {
+  hooks
+    .iter()
+    .filter_map(|(plugin, hook, _)| {
+      let hook = (*hook)?;
+      unsafe { callback::catch_error(|| f(hook)) }
+        .err()
+        .map(|report| format!("{}: {}", plugin, report.message()))
+    })
+    .collect()
+}
+
+#[pg_guard]
+pub unsafe extern "C" fn pgext_check_password_hook(
+  username: *const c_char,
+  shadow_pass: *const c_char,
+  password_type: PasswordType,
+  validuntil_time: Datum,
+  validuntil_null: bool,
+) {
+  if let Some(prev) = ALL_HOOKS.check_password_hook.prev() {
+    prev(username, shadow_pass, password_type, validuntil_time, validuntil_null);
+  }
+  let rejections = collect_rejections(ALL_HOOKS.check_password_hook.hooks(), |hook| {
+    hook(username, shadow_pass, password_type, validuntil_time, validuntil_null)
+  });
+  if !rejections.is_empty() {
+    ErrorReport::new(
+      PgSqlErrorCode::ERRCODE_INVALID_PARAMETER_VALUE,
+      "password rejected",
+      pg_sys::function_name!(),
+    )
+    .set_detail(rejections.join("\n"))
+    .report(PgLogLevel::ERROR);
+  }
+}
+
+#[pg_guard]
+pub unsafe extern "C" fn pgext_client_authentication_hook(port: *mut Port, status: c_int) {
+  if let Some(prev) = ALL_HOOKS.client_authentication_hook.prev() {
+    prev(port, status);
+  }
+  let rejections = collect_rejections(ALL_HOOKS.client_authentication_hook.hooks(), |hook| hook(port, status));
+  if !rejections.is_empty() {
+    ErrorReport::new(
+      PgSqlErrorCode::ERRCODE_INVALID_AUTHORIZATION_SPECIFICATION,
+      "authentication rejected",
+      pg_sys::function_name!(),
+    )
+    .set_detail(rejections.join("\n"))
+    .report(PgLogLevel::FATAL);
+  }
+}
@@ -0,0 +1,140 @@
+//! Background workers registered by plugins through `PgExtApi`
+//!
+//! Static workers are started by the postmaster and cannot be controlled once
+//! registered. Dynamic workers are terminated when their plugin is disabled,
+//! and registered again when it is enabled, which needs a superuser.
+//!
+//! The registry is kept per backend: it holds the static workers, which every
+//! backend inherits from the postmaster, and the dynamic workers registered by
+//! this backend. Dynamic workers can only be registered from a backend, as the
+//! postmaster cannot wait for them.
+
+use std::ffi::CStr;
+
+use pgrx::pg_sys::{self, BackgroundWorker, BackgroundWorkerHandle};
+use pgrx::prelude::*;
+use pgrx::PgMemoryContexts;
+
+use crate::INSTALLED_PLUGINS_STATUS;
+
+pub struct Worker {
+  pub plugin: String,
+  worker: BackgroundWorker,
+  /// `None` for static workers.
+  handle: Option<*mut BackgroundWorkerHandle>,
+}
+
+pub static mut WORKERS: Vec<Worker> = Vec::new();
+
+impl Worker {
+  pub fn name(&self) -> String {
+    unsafe { CStr::from_ptr(self.worker.bgw_name.as_ptr()) }
+      .to_string_lossy()
+      .into_owned()
+  }
+
+  pub fn kind(&self) -> &'static str {
+    if self.handle.is_some() {
+      "dynamic"
+    } else {
+      "static"
+    }
+  }
+
+  /// The pid and status of a dynamic worker.
+  pub fn status(&self) -> (Option<i32>, String) {
+    let handle = match self.handle {
+      None => return (None, "static".to_string()),
+      Some(handle) if handle.is_null() => return (None, "stopped (disabled)".to_string()),
+      Some(handle) => handle,
+    };
+    let mut pid = 0;
+    let status = unsafe { pg_sys::GetBackgroundWorkerPid(handle, &mut pid) };
+    match status {
+      pg_sys::BgwHandleStatus_BGWH_STARTED => (Some(pid), "started".to_string()),
+      pg_sys::BgwHandleStatus_BGWH_NOT_YET_STARTED => (None, "not yet started".to_string()),
+      pg_sys::BgwHandleStatus_BGWH_STOPPED => (None, "stopped".to_string()),
+      _ => (None, "postmaster died".to_string()),
+    }
+  }
+}
+
+/// Register a dynamic worker; the handle lives as long as the backend.
+unsafe fn start(worker: &mut BackgroundWorker) -> *mut BackgroundWorkerHandle {
+  let mut handle = std::ptr::null_mut();
+  PgMemoryContexts::TopMemoryContext.switch_to(|_| {
+    if !pg_sys::RegisterDynamicBackgroundWorker(worker, &mut handle) {
+      handle = std::ptr::null_mut();
+    }
+  });
+  handle
+}
+
+pub unsafe fn register_static(plugin: &str, worker: *mut BackgroundWorker) {
+  if !pg_sys::process_shared_preload_libraries_in_progress {
+    error!("static background workers can only be registered from _PG_init of a preloaded plugin");
+  }
+  pg_sys::RegisterBackgroundWorker(worker);
+  WORKERS.push(Worker {
+    plugin: plugin.to_string(),
+    worker: *worker,
+    handle: None,
+  });
+}
+
+/// Returns null if the worker could not be registered, or if the plugin is
+/// disabled. In the latter case it is started once the plugin is enabled.
+pub unsafe fn register_dynamic(plugin: &str, worker: *mut BackgroundWorker) -> *mut BackgroundWorkerHandle {
+  if !pg_sys::IsUnderPostmaster {
+    error!("dynamic background workers cannot be registered from the postmaster, use register_bgworker");
+  }
+  let mut worker = *worker;
+  let handle = if let Some(&true) = INSTALLED_PLUGINS_STATUS.get(plugin) {
+    start(&mut worker)
+  } else {
+    std::ptr::null_mut()
+  };
+  WORKERS.push(Worker {
+    plugin: plugin.to_string(),
+    worker,
+    handle: Some(handle),
+  });
+  handle
+}
+
+/// Stop the dynamic workers of a plugin that is being disabled, and restart
+/// them when it is enabled again.
+pub unsafe fn change_status(plugin: &str, enabled: bool) {
+  let affected = WORKERS
+    .iter()
+    .any(|worker| worker.plugin == plugin && matches!(worker.handle, Some(handle) if handle.is_null() == enabled));
+  if affected && !pg_sys::superuser() {
+    ereport!(
+      ERROR,
+      PgSqlErrorCode::ERRCODE_INSUFFICIENT_PRIVILEGE,
+      format!(
+        "must be superuser to start or stop the background workers of {}",
+        plugin
+      )
+    );
+  }
+  for worker in WORKERS.iter_mut().filter(|worker| worker.plugin == plugin) {
+    match worker.handle {
+      Some(handle) if !enabled && !handle.is_null() => {
+        pg_sys::TerminateBackgroundWorker(handle);
+        worker.handle = Some(std::ptr::null_mut());
+      }
+      Some(handle) if enabled && handle.is_null() => {
+        worker.handle = Some(start(&mut worker.worker));
+      }
+      _ => {}
+    }
+  }
+}
+
+/// Stop and forget the dynamic workers of a plugin. Static workers keep
+/// running, so they stay listed.
+pub unsafe fn unregister(plugin: &str) {
+  change_status(plugin, false);
+  WORKERS.retain(|worker| worker.plugin != plugin || worker.handle.is_none());
+}
@@ -0,0 +1,71 @@
+//! Wrappers around every call into a plugin
+//!
+//! The hooks generated by `pgext-hook-codegen` and the output rewriters call
+//! plugins through `run`, which charges the callback's memory (see `memory`)
+//! and time (see `timeout`) to the plugin and records it in the trace (see
+//! `trace`), and pass control on to the rest of the chain through `outside`.
+
+use std::panic::AssertUnwindSafe;
+
+use pgrx::pg_sys::panic::{CaughtError, ErrorReportWithLevel};
+use pgrx::{pg_sys, PgTryBuilder};
+
+use crate::{memory, timeout, trace, INSTALLED_PLUGINS_STATUS};
+
+/// Whether the callbacks of `plugin` are called in this backend.
+pub fn enabled(plugin: &str) -> bool {
+  unsafe { matches!(INSTALLED_PLUGINS_STATUS.get(plugin), Some(&true)) && !timeout::is_skipped(plugin) }
+}
+
+/// Run a callback of `plugin` from `hook`.
+pub unsafe fn run<R>(hook: &'static str, plugin: &str, f: impl FnOnce() -> R) -> R {
+  trace::run(hook, plugin, || timeout::run(plugin, || memory::run(plugin, f)))
+}
+
+/// Pass over the callback of a disabled `plugin`.
+pub unsafe fn skip(hook: &'static str, plugin: &str) {
+  trace::skipped(hook, plugin)
+}
+
+/// Run `f` on behalf of the caller of the current plugin callback.
+pub unsafe fn outside<R>(f: impl FnOnce() -> R) -> R {
+  timeout::outside(|| memory::outside(f))
+}
+
+/// Run `f`, and return the error it raises instead of raising it. Within a
+/// transaction `f` runs in a subtransaction, rolled back on error to release
+/// what `f` held, so that the caller can go on.
+pub unsafe fn catch_error<R>(f: impl FnOnce() -> R) -> Result<R, Box<ErrorReportWithLevel>> {
+  let context = pg_sys::CurrentMemoryContext;
+  let owner = pg_sys::CurrentResourceOwner;
+  let subxact = pg_sys::IsTransactionState();
+  if subxact {
+    pg_sys::BeginInternalSubTransaction(std::ptr::null());
+    pg_sys::MemoryContextSwitchTo(context);
+  }
+
+  PgTryBuilder::new(AssertUnwindSafe(|| {
+    let result = pg_sys::ffi::pg_guard_ffi_boundary(f);
+    if subxact {
+      pg_sys::ReleaseCurrentSubTransaction();
+      pg_sys::MemoryContextSwitchTo(context);
+      pg_sys::CurrentResourceOwner = owner;
+    }
+    Ok(result)
+  }))
+  .catch_others(|e| {
+    pg_sys::MemoryContextSwitchTo(context);
+    pg_sys::FlushErrorState();
+    if subxact {
+      pg_sys::RollbackAndReleaseCurrentSubTransaction();
+      pg_sys::MemoryContextSwitchTo(context);
+      pg_sys::CurrentResourceOwner = owner;
+    }
+    match e {
+      CaughtError::PostgresError(report)
+      | CaughtError::ErrorReport(report)
+      | CaughtError::RustPanic { ereport: report, .. } => Err(Box::new(report)),
+    }
+  })
+  .execute()
+}
@@ -0,0 +1,146 @@
+//! Run a query under two sets of enabled plugins and compare the outcome
+//!
+//! Each run happens in its own subtransaction that is rolled back, so that the
+//! query may modify data. Only the plugins in the set are enabled for the run,
+//! in the same way `enable` and `disable` switch them (hooks, output rewriters
+//! and callbacks), except that background workers and settings are left
+//! alone. The previous statuses are restored afterwards, also on error. The
+//! output rewriters apply to the query run through SPI as they would to a
+//! top-level query.
+
+use std::collections::BTreeMap;
+use std::ffi::{CStr, CString};
+use std::panic::{AssertUnwindSafe, UnwindSafe};
+
+use pgrx::pg_sys;
+use pgrx::prelude::*;
+
+use crate::hook_ext::EXECUTOR_RUN_HOOK_NESTED_DEPTH;
+use crate::hook_mgr::ALL_HOOKS;
+use crate::output_rewriter::REWRITTEN_DEPTH;
+use crate::INSTALLED_PLUGINS_STATUS;
+
+/// What a query returned under one set of plugins.
+pub struct Run {
+  /// Rows returned or affected.
+  pub rows: u64,
+  /// The returned rows in text form, sorted.
+  pub results: Vec<Vec<Option<String>>>,
+  pub plan: String,
+}
+
+/// Enable exactly `plugins` (and pgextmgr itself) while running `f`.
+unsafe fn with_plugins<R>(plugins: &[String], f: impl FnOnce() -> R + UnwindSafe) -> R {
+  for plugin in plugins {
+    if !INSTALLED_PLUGINS_STATUS.contains_key(plugin) {
+      panic!("extension {} does not exist", plugin)
+    }
+  }
+  let saved_status = INSTALLED_PLUGINS_STATUS.clone();
+  let saved_rewriters = ALL_HOOKS
+    .rewriters
+    .iter()
+    .map(|(_, _, enabled, _)| *enabled)
+    .collect::<Vec<_>>();
+  let status = |name: &String| name == "__pgext" || plugins.contains(name);
+  INSTALLED_PLUGINS_STATUS
+    .iter_mut()
+    .for_each(|(name, enabled)| *enabled = status(name));
+  ALL_HOOKS
+    .rewriters
+    .iter_mut()
+    .for_each(|(name, _, enabled, _)| *enabled = status(name));
+
+  PgTryBuilder::new(f)
+    .finally(|| {
+      restore(&saved_status, &saved_rewriters);
+    })
+    .execute()
+}
+
+unsafe fn restore(status: &BTreeMap<String, bool>, rewriters: &[bool]) {
+  INSTALLED_PLUGINS_STATUS = status.clone();
+  ALL_HOOKS
+    .rewriters
+    .iter_mut()
+    .zip(rewriters)
+    .for_each(|((_, _, enabled, _), saved)| *enabled = *saved);
+}
+
+/// Run `query` through SPI, returning the number of rows processed and the
+/// returned rows in text form.
+unsafe fn execute(query: &CStr) -> (u64, Vec<Vec<Option<String>>>) {
+  pg_sys::SPI_connect();
+  let ret = pg_sys::SPI_execute(query.as_ptr(), false, 0);
+  if ret < 0 {
+    error!(
+      "could not run \"{}\": {}",
+      query.to_string_lossy(),
+      CStr::from_ptr(pg_sys::SPI_result_code_string(ret)).to_string_lossy()
+    );
+  }
+  let rows = pg_sys::SPI_processed;
+  let mut results = vec![];
+  let tuptable = pg_sys::SPI_tuptable;
+  if !tuptable.is_null() {
+    let tupdesc = (*tuptable).tupdesc;
+    for i in 0..rows as usize {
+      let tuple = *(*tuptable).vals.add(i);
+      let row = (1..=(*tupdesc).natts)
+        .map(|column| {
+          let value = pg_sys::SPI_getvalue(tuple, tupdesc, column);
+          (!value.is_null()).then(|| CStr::from_ptr(value).to_string_lossy().into_owned())
+        })
+        .collect();
+      results.push(row);
+    }
+  }
+  pg_sys::SPI_finish();
+  (rows, results)
+}
+
+/// Run `query` and its plan.
+unsafe fn run(query: &str) -> Run {
+  let explain = CString::new(format!("EXPLAIN (COSTS OFF) {}", query)).unwrap();
+  let plan = execute(&explain)
+    .1
+    .into_iter()
+    .map(|row| row.into_iter().flatten().collect::<Vec<_>>().join(" "))
+    .collect::<Vec<_>>()
+    .join("\n");
+  let (rows, mut results) = execute(&CString::new(query).unwrap());
+  results.sort();
+  Run { rows, results, plan }
+}
+
+/// Run `query` and its plan in a subtransaction that is rolled back, also on
+/// error. Rolling back also closes the SPI connection left open by an error.
+unsafe fn run_rolled_back(query: &str) -> Run {
+  let context = pg_sys::CurrentMemoryContext;
+  let owner = pg_sys::CurrentResourceOwner;
+  let rewritten_depth = REWRITTEN_DEPTH;
+  pg_sys::BeginInternalSubTransaction(std::ptr::null());
+  pg_sys::MemoryContextSwitchTo(context);
+
+  REWRITTEN_DEPTH = EXECUTOR_RUN_HOOK_NESTED_DEPTH + 1;
+  let result = PgTryBuilder::new(AssertUnwindSafe(|| Ok(run(query))))
+    .catch_others(|e| Err(Box::new(e)))
+    .execute();
+  REWRITTEN_DEPTH = rewritten_depth;
+
+  pg_sys::MemoryContextSwitchTo(context);
+  pg_sys::RollbackAndReleaseCurrentSubTransaction();
+  pg_sys::MemoryContextSwitchTo(context);
+  pg_sys::CurrentResourceOwner = owner;
+  result.unwrap_or_else(|e| (*e).rethrow())
+}
+
+/// Run `query` with only `plugins_a` enabled, then with only `plugins_b`.
+pub unsafe fn compare(query: &str, plugins_a: &[String], plugins_b: &[String]) -> (Run, Run) {
+  if query.contains('\0') {
+    error!("query must not contain a null character");
+  }
+  let a = with_plugins(plugins_a, || run_rolled_back(query));
+  let b = with_plugins(plugins_b, || run_rolled_back(query));
+  (a, b)
+}
@@ -0,0 +1,153 @@
+//! Custom scan providers
+//!
+//! Plugins declare the custom scan methods they provide, so that pgextmgr
+//! knows which plugin a CustomPath or CustomScan belongs to. pgextmgr owns
+//! `set_rel_pathlist_hook`: it calls the hooks of all enabled plugins, then
+//! drops the CustomPaths of disabled plugins. The CustomScans that end up in
+//! executed plans are counted per method, and shown in EXPLAIN.
+
+use std::collections::BTreeMap;
+use std::ffi::{c_int, CStr, CString};
+
+#[cfg(feature = "pg13")]
+use pgrx::pg_sys::ModifyTable;
+use pgrx::pg_sys::{
+  self, Append, BitmapAnd, BitmapOr, CustomPath, CustomPathMethods, CustomScan, ExplainState, Index, MergeAppend, Path,
+  Plan, PlannerInfo, QueryDesc, RangeTblEntry, RelOptInfo, SubqueryScan,
+};
+use pgrx::prelude::*;
+use pgrx::PgList;
+
+use crate::callback::enabled;
+use crate::hook_mgr::ALL_HOOKS;
+
+/// (plugin, custom scan name)
+pub static mut CUSTOM_SCANS: Vec<(String, String)> = Vec::new();
+
+/// How often a custom scan has been chosen in an executed plan, by name.
+pub static mut CHOSEN: BTreeMap<String, i64> = BTreeMap::new();
+
+pub unsafe fn register(plugin: &str, methods: *const CustomPathMethods) {
+  let name = CStr::from_ptr((*methods).CustomName).to_string_lossy().into_owned();
+  if let Some((owner, _)) = CUSTOM_SCANS.iter().find(|(_, x)| *x == name) {
+    error!("custom scan {} is already registered by plugin {}", name, owner);
+  }
+  if CUSTOM_SCANS.is_empty() {
+    // Only add the EXPLAIN section once there is something to report.
+    ALL_HOOKS
+      .explain_callbacks
+      .register("__pgext".to_string(), Some(explain), ());
+  }
+  CUSTOM_SCANS.push((plugin.to_string(), name));
+}
+
+pub unsafe fn unregister(plugin: &str) {
+  CUSTOM_SCANS.retain(|(owner, _)| owner != plugin);
+}
+
+/// The plugin providing a custom scan, if it has been registered.
+pub fn provider(name: &str) -> Option<&'static str> {
+  unsafe { CUSTOM_SCANS.iter() }
+    .find(|(_, x)| *x == name)
+    .map(|(plugin, _)| plugin.as_str())
+}
+
+/// Remove the CustomPaths of disabled plugins from a path list.
+pub unsafe fn filter_paths(list: *mut pg_sys::List) -> *mut pg_sys::List {
+  let disabled = PgList::<Path>::from_pg(list)
+    .iter_ptr()
+    .filter(|&path| {
+      if (*path).type_ != pg_sys::NodeTag_T_CustomPath {
+        return false;
+      }
+      let methods = (*(path as *mut CustomPath)).methods;
+      let name = CStr::from_ptr((*methods).CustomName).to_string_lossy();
+      matches!(provider(&name), Some(plugin) if !enabled(plugin))
+    })
+    .collect::<Vec<_>>();
+  disabled
+    .into_iter()
+    .fold(list, |list, path| pg_sys::list_delete_ptr(list, path as *mut _))
+}
+
+#[pg_guard]
+pub unsafe extern "C" fn pgext_set_rel_pathlist_hook(
+  root: *mut PlannerInfo,
+  rel: *mut RelOptInfo,
+  rti: Index,
+  rte: *mut RangeTblEntry,
+) {
+  for (plugin, hook, _) in ALL_HOOKS.set_rel_pathlist_hook.hooks() {
+    if let (true, Some(hook)) = (enabled(plugin), hook) {
+      hook(root, rel, rti, rte);
+    }
+  }
+  (*rel).pathlist = filter_paths((*rel).pathlist);
+  (*rel).partial_pathlist = filter_paths((*rel).partial_pathlist);
+}
+
+unsafe fn for_each_custom_scan(plan: *mut Plan, f: &mut impl FnMut(*mut CustomScan)) {
+  if plan.is_null() {
+    return;
+  }
+  let children = match (*plan).type_ {
+    pg_sys::NodeTag_T_CustomScan => {
+      f(plan as *mut CustomScan);
+      (*(plan as *mut CustomScan)).custom_plans
+    }
+    pg_sys::NodeTag_T_Append => (*(plan as *mut Append)).appendplans,
+    pg_sys::NodeTag_T_MergeAppend => (*(plan as *mut MergeAppend)).mergeplans,
+    pg_sys::NodeTag_T_BitmapAnd => (*(plan as *mut BitmapAnd)).bitmapplans,
+    pg_sys::NodeTag_T_BitmapOr => (*(plan as *mut BitmapOr)).bitmapplans,
+    // before PostgreSQL 14, the plans to modify hang off ModifyTable itself
+    #[cfg(feature = "pg13")]
+    pg_sys::NodeTag_T_ModifyTable => (*(plan as *mut ModifyTable)).plans,
+    pg_sys::NodeTag_T_SubqueryScan => {
+      for_each_custom_scan((*(plan as *mut SubqueryScan)).subplan, f);
+      std::ptr::null_mut()
+    }
+    _ => std::ptr::null_mut(),
+  };
+  for child in PgList::<Plan>::from_pg(children).iter_ptr() {
+    for_each_custom_scan(child, f);
+  }
+  for_each_custom_scan((*plan).lefttree, f);
+  for_each_custom_scan((*plan).righttree, f);
+}
+
+/// The names of all custom scans in the plan of a query.
+unsafe fn custom_scans(query_desc: *mut QueryDesc) -> Vec<String> {
+  let stmt = (*query_desc).plannedstmt;
+  let mut names = vec![];
+  let mut collect = |scan: *mut CustomScan| {
+    names.push(
+      CStr::from_ptr((*(*scan).methods).CustomName)
+        .to_string_lossy()
+        .into_owned(),
+    );
+  };
+  for_each_custom_scan((*stmt).planTree, &mut collect);
+  for subplan in PgList::<Plan>::from_pg((*stmt).subplans).iter_ptr() {
+    for_each_custom_scan(subplan, &mut collect);
+  }
+  names
+}
+
+/// Called from the ExecutorStart hook of pgextmgr.
+pub unsafe fn count_chosen(query_desc: *mut QueryDesc, eflags: c_int) {
+  if CUSTOM_SCANS.is_empty() || eflags & pg_sys::EXEC_FLAG_EXPLAIN_ONLY as c_int != 0 {
+    return;
+  }
+  for name in custom_scans(query_desc) {
+    *CHOSEN.entry(name).or_insert(0) += 1;
+  }
+}
+
+/// Explain callback of pgextmgr: which plugin provided each custom scan.
+pub unsafe extern "C" fn explain(query_desc: *mut QueryDesc, es: *mut ExplainState) {
+  for name in custom_scans(query_desc) {
+    let label = CString::new("Custom Scan").unwrap();
+    let value = CString::new(format!("{} ({})", name, provider(&name).unwrap_or("unknown"))).unwrap();
+    pg_sys::ExplainPropertyText(label.as_ptr(), value.as_ptr(), es);
+  }
+}
@@ -0,0 +1,95 @@
+//! Plugin-declared dependencies and conflicts, checked once preloading finishes
+
+use pgrx::pg_sys;
+use pgrx::pg_sys::panic::ErrorReport;
+use pgrx::prelude::*;
+
+use crate::INSTALLED_PLUGINS;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum DependencyKind {
+  /// The other plugin must be loaded before this one.
+  Requires,
+  /// The other plugin must not be loaded together with this one.
+  ConflictsWith,
+}
+
+impl DependencyKind {
+  pub fn as_str(&self) -> &'static str {
+    match self {
+      DependencyKind::Requires => "requires",
+      DependencyKind::ConflictsWith => "conflicts",
+    }
+  }
+}
+
+/// (plugin, kind, other plugin)
+pub static mut DEPENDENCIES: Vec<(String, DependencyKind, String)> = Vec::new();
+
+pub unsafe fn add_dependency(plugin: &str, kind: DependencyKind, other: &str) {
+  DEPENDENCIES.push((plugin.to_string(), kind, other.to_string()));
+}
+
+/// Check all declarations against the plugins that have been loaded. A missing
+/// or misordered dependency is FATAL, a conflict is a WARNING.
+pub unsafe fn violations() -> Vec<(PgLogLevel, ErrorReport)> {
+  let position = |name: &str| INSTALLED_PLUGINS.iter().position(|x| x == name);
+  let mut violations = vec![];
+  for (plugin, kind, other) in DEPENDENCIES.iter() {
+    match kind {
+      DependencyKind::Requires => match (position(plugin), position(other)) {
+        (_, None) => violations.push((
+          PgLogLevel::FATAL,
+          ErrorReport::new(
+            PgSqlErrorCode::ERRCODE_CONFIG_FILE_ERROR,
+            format!(
+              "plugin \"{}\" requires plugin \"{}\", which is not loaded",
+              plugin, other
+            ),
+            pg_sys::function_name!(),
+          )
+          .set_hint(format!(
+            "Add \"{}\" to shared_preload_libraries before \"{}\".",
+            other, plugin
+          )),
+        )),
+        (Some(this), Some(that)) if that > this => violations.push((
+          PgLogLevel::FATAL,
+          ErrorReport::new(
+            PgSqlErrorCode::ERRCODE_CONFIG_FILE_ERROR,
+            format!("plugin \"{}\" requires plugin \"{}\" to be loaded first", plugin, other),
+            pg_sys::function_name!(),
+          )
+          .set_hint(format!(
+            "Move \"{}\" before \"{}\" in shared_preload_libraries.",
+            other, plugin
+          )),
+        )),
+        _ => {}
+      },
+      DependencyKind::ConflictsWith => {
+        if position(other).is_some() {
+          violations.push((
+            PgLogLevel::WARNING,
+            ErrorReport::new(
+              PgSqlErrorCode::ERRCODE_CONFIG_FILE_ERROR,
+              format!("plugin \"{}\" conflicts with plugin \"{}\"", plugin, other),
+              pg_sys::function_name!(),
+            )
+            .set_detail(format!("The conflict is declared by \"{}\".", plugin))
+            .set_hint("Remove one of them from shared_preload_libraries."),
+          ))
+        }
+      }
+    }
+  }
+  violations
+}
+
+/// Report the violations. Called from `shmem_request_hook`, once all plugins
+/// have been loaded.
+pub unsafe fn check_dependencies() {
+  for (level, report) in violations() {
+    report.report(level);
+  }
+}
@@ -0,0 +1,159 @@
+//! Fan-out of `emit_log_hook`
+//!
+//! `emit_log_hook` runs before the message is written to the server log, so an
+//! error raised by a plugin would replace the message, turning a `LOG` into an
+//! `ERROR` or keeping a `FATAL` from ending the backend. pgextmgr swallows such
+//! errors instead: it logs that the plugin failed and calls the remaining
+//! plugins, and the message is reported at its own level.
+//!
+//! Raising the error releases the message's strings and the flush pops it off
+//! the error stack, so pgextmgr keeps a copy of the message while the plugins
+//! are called and puts it back afterwards. Errors pending below the message,
+//! which are only there when a message is reported while another error is
+//! being handled, are flushed along with it.
+
+use std::ffi::{c_char, c_int};
+use std::panic::AssertUnwindSafe;
+
+use pgrx::pg_sys::panic::{CaughtError, ErrorReport};
+use pgrx::pg_sys::{self, AsPgCStr, ErrorData};
+use pgrx::prelude::*;
+use pgrx::{PgMemoryContexts, PgTryBuilder};
+
+use crate::callback::enabled;
+use crate::hook_mgr::ALL_HOOKS;
+
+/// Which messages a plugin wants to see.
+#[derive(Default)]
+pub struct LogFilter {
+  min_elevel: c_int,
+  /// Encoded SQLSTATEs (see `MAKE_SQLSTATE`), empty for all.
+  sqlstates: Vec<c_int>,
+}
+
+impl LogFilter {
+  pub fn new(min_elevel: c_int, sqlstates: &[c_int]) -> Self {
+    Self {
+      min_elevel,
+      sqlstates: sqlstates.to_vec(),
+    }
+  }
+
+  fn matches(&self, edata: &ErrorData) -> bool {
+    edata.elevel >= self.min_elevel && (self.sqlstates.is_empty() || self.sqlstates.contains(&edata.sqlerrcode))
+  }
+}
+
+/// Set while the plugins are being called. Messages the plugins report
+/// themselves go straight to the log, so that a plugin logging from its hook
+/// does not recurse.
+static mut IN_EMIT_LOG: bool = false;
+
+/// Holds the copy of the message while the plugins are called.
+static mut EMIT_LOG_CONTEXT: pg_sys::MemoryContext = std::ptr::null_mut();
+
+struct EmitLogGuard;
+
+impl Drop for EmitLogGuard {
+  fn drop(&mut self) {
+    unsafe {
+      IN_EMIT_LOG = false;
+      pg_sys::MemoryContextReset(EMIT_LOG_CONTEXT);
+    }
+  }
+}
+
+extern "C" {
+  fn errstart(elevel: c_int, domain: *const c_char) -> bool;
+}
+
+/// Puts the message back on the error stack after a plugin's error was
+/// flushed, refilling its slot from the copy in `saved`.
+unsafe fn restore(edata: *mut ErrorData, saved: *const ErrorData) {
+  // pushed whether or not an ERROR would be logged, unlike lower levels
+  errstart(pg_sys::ERROR as c_int, (*saved).domain);
+  PgMemoryContexts::For(pg_sys::ErrorContext).switch_to(|_| {
+    *edata = *saved;
+    let edata = &mut *edata;
+    for field in [
+      &mut edata.message,
+      &mut edata.detail,
+      &mut edata.detail_log,
+      &mut edata.hint,
+      &mut edata.context,
+      &mut edata.backtrace,
+      &mut edata.schema_name,
+      &mut edata.table_name,
+      &mut edata.column_name,
+      &mut edata.datatype_name,
+      &mut edata.constraint_name,
+      &mut edata.internalquery,
+    ] {
+      if !field.is_null() {
+        *field = pg_sys::pstrdup(*field);
+      }
+    }
+    edata.assoc_context = pg_sys::ErrorContext;
+  });
+}
+
+#[pg_guard]
+pub unsafe extern "C" fn pgext_emit_log_hook(edata: *mut ErrorData) {
+  if IN_EMIT_LOG {
+    return;
+  }
+  let hooks = ALL_HOOKS
+    .emit_log_hook
+    .hooks()
+    .iter()
+    .filter(|(plugin, _, filter)| enabled(plugin) && filter.matches(&*edata))
+    .filter_map(|(plugin, hook, _)| hook.map(|hook| (plugin.clone(), hook)))
+    .collect::<Vec<_>>();
+  if hooks.is_empty() {
+    return;
+  }
+
+  if EMIT_LOG_CONTEXT.is_null() {
+    EMIT_LOG_CONTEXT = PgMemoryContexts::TopMemoryContext.switch_to(|_| {
+      pg_sys::AllocSetContextCreateExtended(
+        pg_sys::TopMemoryContext,
+        "pgext emit_log".as_pg_cstr(),
+        pg_sys::ALLOCSET_DEFAULT_MINSIZE as usize,
+        pg_sys::ALLOCSET_DEFAULT_INITSIZE as usize,
+        pg_sys::ALLOCSET_DEFAULT_MAXSIZE as usize,
+      )
+    });
+  }
+  IN_EMIT_LOG = true;
+  let _guard = EmitLogGuard;
+
+  // called in ErrorContext, which is reset when a plugin raises an error
+  let error_context = pg_sys::CurrentMemoryContext;
+  PgMemoryContexts::For(EMIT_LOG_CONTEXT).switch_to(|_| {
+    let saved = pg_sys::CopyErrorData();
+    for (plugin, hook) in hooks {
+      let failed = PgTryBuilder::new(AssertUnwindSafe(|| {
+        pg_sys::ffi::pg_guard_ffi_boundary(|| PgMemoryContexts::For(error_context).switch_to(|_| hook(edata)));
+        false
+      }))
+      .catch_others(|e| {
+        let message = match e {
+          CaughtError::PostgresError(report)
+          | CaughtError::ErrorReport(report)
+          | CaughtError::RustPanic { ereport: report, .. } => report.message().to_string(),
+        };
+        ErrorReport::new(
+          PgSqlErrorCode::ERRCODE_INTERNAL_ERROR,
+          format!("emit_log_hook of plugin {} failed: {}", plugin, message),
+          pg_sys::function_name!(),
+        )
+        .report(PgLogLevel::LOG);
+        true
+      })
+      .execute();
+      if failed {
+        restore(edata, saved);
+      }
+    }
+  });
+}
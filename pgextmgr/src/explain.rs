@@ -0,0 +1,125 @@
+//! EXPLAIN sections of plugins
+//!
+//! pgextmgr installs an `ExplainOneQuery_hook` that remembers which query is
+//! being explained, and passes it on to the hook installed before it, or plans
+//! and explains it like PostgreSQL does. When the query reaches ExecutorEnd
+//! (which `ExplainOnePlan` calls while the "Query" group is still open), every
+//! plugin that registered an explain callback may add properties under its own
+//! group in a "pgext" section, in load order. The query is recognized by its
+//! source text, which `ExplainOnePlan` passes on to the executor as is.
+
+use std::ffi::{c_char, c_int, CString};
+
+use pgrx::pg_sys::{self, ExplainState, IntoClause, Oid, ParamListInfo, Query, QueryDesc, QueryEnvironment};
+use pgrx::prelude::*;
+use pgrx::PgTryBuilder;
+
+use crate::callback::enabled;
+use crate::hook_mgr::ALL_HOOKS;
+
+pub type ExplainCallback = Option<unsafe extern "C" fn(query_desc: *mut QueryDesc, es: *mut ExplainState)>;
+
+/// The EXPLAIN statements being run and their source text, innermost last.
+static mut EXPLAINING: Vec<(*mut ExplainState, *const c_char)> = Vec::new();
+
+static mut PREV_EXPLAIN_ONE_QUERY_HOOK: pg_sys::ExplainOneQuery_hook_type = None;
+
+/// What `ExplainOneQuery` does without a hook.
+unsafe fn standard_explain_one_query(
+  query: *mut Query,
+  cursor_options: c_int,
+  into: *mut IntoClause,
+  es: *mut ExplainState,
+  query_string: *const c_char,
+  params: ParamListInfo,
+  query_env: *mut QueryEnvironment,
+) {
+  let plan_start = std::time::Instant::now();
+  let bufusage_start = pg_sys::pgBufferUsage;
+  let plan = pg_sys::pg_plan_query(query, query_string, cursor_options, params);
+  let elapsed = plan_start.elapsed();
+  let plan_duration = pg_sys::instr_time {
+    tv_sec: elapsed.as_secs() as _,
+    tv_nsec: elapsed.subsec_nanos() as _,
+  };
+  let mut bufusage = pg_sys::BufferUsage::default();
+  pg_sys::BufferUsageAccumDiff(&mut bufusage, &pg_sys::pgBufferUsage, &bufusage_start);
+
+  pg_sys::ExplainOnePlan(
+    plan,
+    into,
+    es,
+    query_string,
+    params,
+    query_env,
+    &plan_duration,
+    if (*es).buffers { &bufusage } else { std::ptr::null() },
+  )
+}
+
+#[pg_guard]
+unsafe extern "C" fn pgext_explain_one_query(
+  query: *mut Query,
+  cursor_options: c_int,
+  into: *mut IntoClause,
+  es: *mut ExplainState,
+  query_string: *const c_char,
+  params: ParamListInfo,
+  query_env: *mut QueryEnvironment,
+) {
+  EXPLAINING.push((es, query_string));
+  PgTryBuilder::new(|| match PREV_EXPLAIN_ONE_QUERY_HOOK {
+    Some(prev) => prev(query, cursor_options, into, es, query_string, params, query_env),
+    None => standard_explain_one_query(query, cursor_options, into, es, query_string, params, query_env),
+  })
+  .finally(|| {
+    EXPLAINING.pop();
+  })
+  .execute();
+}
+
+/// Called from the ExecutorEnd hook of pgextmgr, before any plugin.
+pub unsafe fn explain_sections(query_desc: *mut QueryDesc) {
+  let es = match EXPLAINING.last() {
+    Some(&(es, query_string)) if query_string == (*query_desc).sourceText => es,
+    _ => return,
+  };
+  let callbacks = ALL_HOOKS
+    .explain_callbacks
+    .registered()
+    .filter(|(plugin, _, _)| enabled(plugin))
+    .filter_map(|(plugin, callback, _)| callback.map(|callback| (plugin.clone(), callback)))
+    .collect::<Vec<_>>();
+  if callbacks.is_empty() {
+    return;
+  }
+  let section = CString::new("pgext").unwrap();
+  let group = CString::new("Plugin").unwrap();
+  pg_sys::ExplainOpenGroup(section.as_ptr(), section.as_ptr(), true, es);
+  for (plugin, callback) in callbacks {
+    let plugin = CString::new(plugin).unwrap();
+    pg_sys::ExplainOpenGroup(group.as_ptr(), plugin.as_ptr(), true, es);
+    callback(query_desc, es);
+    pg_sys::ExplainCloseGroup(group.as_ptr(), plugin.as_ptr(), true, es);
+  }
+  pg_sys::ExplainCloseGroup(section.as_ptr(), section.as_ptr(), true, es);
+}
+
+/// The first plugin returning a name wins.
+#[pg_guard]
+pub unsafe extern "C" fn pgext_explain_get_index_name_hook(index_id: Oid) -> *const c_char {
+  for (plugin, hook, _) in ALL_HOOKS.explain_get_index_name_hook.hooks() {
+    if let (true, Some(hook)) = (enabled(plugin), hook) {
+      let name = hook(index_id);
+      if !name.is_null() {
+        return name;
+      }
+    }
+  }
+  std::ptr::null()
+}
+
+pub unsafe fn init() {
+  PREV_EXPLAIN_ONE_QUERY_HOOK = pg_sys::ExplainOneQuery_hook;
+  pg_sys::ExplainOneQuery_hook = Some(pgext_explain_one_query);
+}
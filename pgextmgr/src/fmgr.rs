@@ -0,0 +1,100 @@
+//! `needs_fmgr_hook` and `fmgr_hook`
+//!
+//! A function is routed through `fmgr_hook` if any plugin needs it. Each plugin
+//! then only sees the functions it asked for (plus the ones PostgreSQL routes
+//! through `fmgr_hook` anyway, i.e. SECURITY DEFINER functions and functions
+//! with SET clauses), and gets its own private datum. Plugins are started in
+//! load order and ended in reverse order, so that they nest like a chain would.
+//! Plugins such as sepgsql enforce security through these hooks, so they are
+//! called whether the plugin is enabled or not.
+
+use pgrx::pg_sys::{self, Datum, FmgrHookEventType, FmgrInfo, Oid};
+use pgrx::prelude::*;
+
+use crate::hook_mgr::ALL_HOOKS;
+
+/// The private datum of the multiplexer, allocated in the function's memory
+/// context on the first call.
+#[repr(C)]
+struct FmgrState {
+  len: usize,
+  /// Whether the plugin's `fmgr_hook` should see this function.
+  needed: *mut bool,
+  /// Whether the plugin was started for the current call.
+  started: *mut bool,
+  /// The private datum of each plugin.
+  args: *mut Datum,
+}
+
+unsafe fn needed_by(plugin: &str, fn_oid: Oid) -> bool {
+  ALL_HOOKS
+    .needs_fmgr_hook
+    .hooks()
+    .iter()
+    .any(|(name, hook, _)| name == plugin && matches!(hook, Some(hook) if hook(fn_oid)))
+}
+
+/// Whether PostgreSQL routes the function through `fmgr_hook` regardless of
+/// `needs_fmgr_hook`.
+unsafe fn routed_anyway(fn_oid: Oid) -> bool {
+  let tuple = pg_sys::SearchSysCache1(pg_sys::SysCacheIdentifier_PROCOID as _, fn_oid.into());
+  if tuple.is_null() {
+    return false;
+  }
+  let proc = pg_sys::heap_tuple_get_struct::<pg_sys::FormData_pg_proc>(tuple);
+  let routed =
+    (*proc).prosecdef || !pg_sys::heap_attisnull(tuple, pg_sys::Anum_pg_proc_proconfig as _, std::ptr::null_mut());
+  pg_sys::ReleaseSysCache(tuple);
+  routed
+}
+
+unsafe fn state_for(flinfo: *mut FmgrInfo, arg: *mut Datum) -> *mut FmgrState {
+  if (*arg).is_null() {
+    let len = ALL_HOOKS.fmgr_hook.hooks().len();
+    let fn_oid = (*flinfo).fn_oid;
+    let routed = routed_anyway(fn_oid);
+    let cxt = (*flinfo).fn_mcxt;
+    let state = pg_sys::MemoryContextAllocZero(cxt, std::mem::size_of::<FmgrState>()) as *mut FmgrState;
+    (*state).len = len;
+    (*state).needed = pg_sys::MemoryContextAllocZero(cxt, std::mem::size_of::<bool>() * len.max(1)) as *mut bool;
+    (*state).started = pg_sys::MemoryContextAllocZero(cxt, std::mem::size_of::<bool>() * len.max(1)) as *mut bool;
+    (*state).args = pg_sys::MemoryContextAllocZero(cxt, std::mem::size_of::<Datum>() * len.max(1)) as *mut Datum;
+    for (i, (plugin, _, _)) in ALL_HOOKS.fmgr_hook.hooks().iter().enumerate() {
+      *(*state).needed.add(i) = routed || needed_by(plugin, fn_oid);
+    }
+    *arg = Datum::from(state);
+  }
+  (*arg).cast_mut_ptr()
+}
+
+#[pg_guard]
+pub unsafe extern "C" fn pgext_needs_fmgr_hook(fn_oid: Oid) -> bool {
+  ALL_HOOKS
+    .needs_fmgr_hook
+    .hooks()
+    .iter()
+    .any(|(_, hook, _)| matches!(hook, Some(hook) if hook(fn_oid)))
+}
+
+#[pg_guard]
+pub unsafe extern "C" fn pgext_fmgr_hook(event: FmgrHookEventType, flinfo: *mut FmgrInfo, arg: *mut Datum) {
+  let state = state_for(flinfo, arg);
+  // plugins cannot be added after startup, so the positions are stable
+  let hooks = ALL_HOOKS.fmgr_hook.hooks();
+  let len = (*state).len;
+  if event == pg_sys::FmgrHookEventType_FHET_START {
+    for (i, (_, hook, _)) in hooks.iter().enumerate().take(len) {
+      let start = *(*state).needed.add(i);
+      *(*state).started.add(i) = start;
+      if let (true, Some(hook)) = (start, hook) {
+        hook(event, flinfo, (*state).args.add(i));
+      }
+    }
+  } else {
+    for (i, (_, hook, _)) in hooks.iter().enumerate().take(len).rev() {
+      if let (true, Some(hook)) = (*(*state).started.add(i), hook) {
+        hook(event, flinfo, (*state).args.add(i));
+      }
+    }
+  }
+}
@@ -0,0 +1,173 @@
+//! Settings defined by plugins through `PgExtApi`
+//!
+//! Every setting is prefixed with the plugin name, so that pgextmgr knows which
+//! plugin a setting belongs to.
+
+use std::ffi::{c_char, c_int, CStr, CString};
+
+use pgrx::pg_sys::{self, config_enum_entry, GucContext};
+use pgrx::prelude::*;
+
+/// (plugin, full name of the setting, type)
+pub static mut PLUGIN_SETTINGS: Vec<(String, String, &'static str)> = Vec::new();
+
+unsafe fn register(plugin: &str, name: *const c_char, kind: &'static str) -> CString {
+  let name = format!("{}.{}", plugin, CStr::from_ptr(name).to_string_lossy());
+  PLUGIN_SETTINGS.push((plugin.to_string(), name.clone(), kind));
+  CString::new(name).unwrap()
+}
+
+#[allow(clippy::too_many_arguments)]
+pub unsafe fn define_bool(
+  plugin: &str,
+  name: *const c_char,
+  short_desc: *const c_char,
+  long_desc: *const c_char,
+  value: *mut bool,
+  boot_value: bool,
+  context: GucContext,
+  flags: c_int,
+) {
+  let name = register(plugin, name, "bool");
+  pg_sys::DefineCustomBoolVariable(
+    name.as_ptr(),
+    short_desc,
+    long_desc,
+    value,
+    boot_value,
+    context,
+    flags,
+    None,
+    None,
+    None,
+  );
+}
+
+#[allow(clippy::too_many_arguments)]
+pub unsafe fn define_int(
+  plugin: &str,
+  name: *const c_char,
+  short_desc: *const c_char,
+  long_desc: *const c_char,
+  value: *mut c_int,
+  boot_value: c_int,
+  min_value: c_int,
+  max_value: c_int,
+  context: GucContext,
+  flags: c_int,
+) {
+  let name = register(plugin, name, "integer");
+  pg_sys::DefineCustomIntVariable(
+    name.as_ptr(),
+    short_desc,
+    long_desc,
+    value,
+    boot_value,
+    min_value,
+    max_value,
+    context,
+    flags,
+    None,
+    None,
+    None,
+  );
+}
+
+#[allow(clippy::too_many_arguments)]
+pub unsafe fn define_string(
+  plugin: &str,
+  name: *const c_char,
+  short_desc: *const c_char,
+  long_desc: *const c_char,
+  value: *mut *mut c_char,
+  boot_value: *const c_char,
+  context: GucContext,
+  flags: c_int,
+) {
+  let name = register(plugin, name, "string");
+  pg_sys::DefineCustomStringVariable(
+    name.as_ptr(),
+    short_desc,
+    long_desc,
+    value,
+    boot_value,
+    context,
+    flags,
+    None,
+    None,
+    None,
+  );
+}
+
+#[allow(clippy::too_many_arguments)]
+pub unsafe fn define_enum(
+  plugin: &str,
+  name: *const c_char,
+  short_desc: *const c_char,
+  long_desc: *const c_char,
+  value: *mut c_int,
+  boot_value: c_int,
+  options: *const config_enum_entry,
+  context: GucContext,
+  flags: c_int,
+) {
+  let name = register(plugin, name, "enum");
+  pg_sys::DefineCustomEnumVariable(
+    name.as_ptr(),
+    short_desc,
+    long_desc,
+    value,
+    boot_value,
+    options,
+    context,
+    flags,
+    None,
+    None,
+    None,
+  );
+}
+
+/// Forget the settings of an unregistered plugin. PostgreSQL cannot remove
+/// a setting, so they stay defined but are no longer listed.
+pub unsafe fn unregister(plugin: &str) {
+  PLUGIN_SETTINGS.retain(|(name, _, _)| name != plugin);
+}
+
+/// Reserve the plugin's prefix once it has defined all of its settings, so that
+/// misspelled settings are reported.
+pub unsafe fn after_init(plugin: &str) {
+  if PLUGIN_SETTINGS.iter().any(|(name, _, _)| name == plugin) {
+    let prefix = CString::new(plugin).unwrap();
+    #[cfg(feature = "pg15")]
+    pg_sys::MarkGUCPrefixReserved(prefix.as_ptr());
+    #[cfg(not(feature = "pg15"))]
+    pg_sys::EmitWarningsOnPlaceholders(prefix.as_ptr());
+  }
+}
+
+/// The current value of a setting, as shown by `SHOW`.
+pub unsafe fn current_value(name: &str) -> Option<String> {
+  let name = CString::new(name).unwrap();
+  let value = pg_sys::GetConfigOption(name.as_ptr(), true, false);
+  if value.is_null() {
+    None
+  } else {
+    Some(CStr::from_ptr(value).to_string_lossy().into_owned())
+  }
+}
+
+/// Tell the user which settings stop taking effect when the plugin is disabled.
+pub unsafe fn report_inactive_settings(plugin: &str) {
+  let settings = PLUGIN_SETTINGS
+    .iter()
+    .filter(|(name, _, _)| name == plugin)
+    .map(|(_, setting, _)| setting.as_str())
+    .collect::<Vec<_>>();
+  if !settings.is_empty() {
+    notice!(
+      "settings of plugin {} no longer take effect: {}",
+      plugin,
+      settings.join(", ")
+    );
+  }
+}
@@ -1,15 +1,34 @@
 #![allow(clippy::missing_safety_doc)]
 
+mod annotation;
 pub mod api;
+mod auth;
+mod bgworker;
+mod callback;
+mod compare;
+mod custom_scan;
+mod dependency;
+mod emit_log;
+mod explain;
+mod fmgr;
+mod guc;
 mod hook_ext;
 mod hook_mgr;
-mod hook_pregen;
-mod output_rewriter;
+mod memory;
+pub mod output_rewriter;
 mod pgext;
+mod plpgsql;
+mod query_id;
+mod security;
+mod shmem;
+mod timeout;
+mod trace;
+mod xact;
 
 use std::collections::BTreeMap;
 
 use hook_mgr::ALL_HOOKS;
+use output_rewriter::RewriterMode;
 use pgrx::pg_sys::AsPgCStr;
 use pgrx::prelude::*;
 
@@ -17,6 +36,7 @@ pgrx::pg_module_magic!();
 
 static mut INSTALLED_PLUGINS: Vec<String> = Vec::new();
 static mut INSTALLED_PLUGINS_STATUS: BTreeMap<String, bool> = BTreeMap::new();
+static mut INSTALLED_PLUGIN_APIS: BTreeMap<String, *mut api::PgExtApi> = BTreeMap::new();
 const ENABLE_LOGGING: bool = false;
 
 #[pg_guard]
@@ -25,45 +45,92 @@ pub unsafe extern "C" fn __pgext_before_init(name: *const pgrx::ffi::c_char) ->
   let plugin_name = std::ffi::CStr::from_ptr(name).to_string_lossy().into_owned();
   INSTALLED_PLUGINS.push(plugin_name.clone());
   INSTALLED_PLUGINS_STATUS.insert(plugin_name.clone(), true);
-  pgrx::pg_sys::planner_hook = ALL_HOOKS
-    .planner_hook
-    .before_register(Some(hook_ext::pgext_planner_hook), pgrx::pg_sys::planner_hook);
-  pgrx::pg_sys::ExecutorRun_hook = ALL_HOOKS
-    .executor_run_hook
-    .before_register(Some(hook_ext::pgext_executor_run_hook), pgrx::pg_sys::ExecutorRun_hook);
-  pgrx::pg_sys::ExecutorStart_hook = ALL_HOOKS.executor_start_hook.before_register(
-    Some(hook_ext::pgext_executor_start_hook),
-    pgrx::pg_sys::ExecutorStart_hook,
+  ALL_HOOKS.chained.before_init();
+  pgrx::pg_sys::emit_log_hook = ALL_HOOKS
+    .emit_log_hook
+    .before_register(Some(emit_log::pgext_emit_log_hook), pgrx::pg_sys::emit_log_hook);
+  pgrx::pg_sys::object_access_hook = ALL_HOOKS.object_access_hook.before_register(
+    Some(security::pgext_object_access_hook),
+    pgrx::pg_sys::object_access_hook,
   );
-  pgrx::pg_sys::ExecutorEnd_hook = ALL_HOOKS
-    .executor_end_hook
-    .before_register(Some(hook_ext::pgext_executor_end_hook), pgrx::pg_sys::ExecutorEnd_hook);
-  pgrx::pg_sys::ExecutorFinish_hook = ALL_HOOKS.executor_finish_hook.before_register(
-    Some(hook_ext::pgext_executor_finish_hook),
-    pgrx::pg_sys::ExecutorFinish_hook,
+  pgrx::pg_sys::ExecutorCheckPerms_hook = ALL_HOOKS.executor_check_perms_hook.before_register(
+    Some(security::pgext_executor_check_perms_hook),
+    pgrx::pg_sys::ExecutorCheckPerms_hook,
   );
-  Box::leak(Box::new(api::PgExtApi::new(plugin_name)))
+  pgrx::pg_sys::row_security_policy_hook_permissive = ALL_HOOKS.row_security_policy_hook_permissive.before_register(
+    Some(security::pgext_row_security_policy_hook_permissive),
+    pgrx::pg_sys::row_security_policy_hook_permissive,
+  );
+  pgrx::pg_sys::row_security_policy_hook_restrictive = ALL_HOOKS.row_security_policy_hook_restrictive.before_register(
+    Some(security::pgext_row_security_policy_hook_restrictive),
+    pgrx::pg_sys::row_security_policy_hook_restrictive,
+  );
+  pgrx::pg_sys::check_password_hook = ALL_HOOKS
+    .check_password_hook
+    .before_register(Some(auth::pgext_check_password_hook), pgrx::pg_sys::check_password_hook);
+  auth::ClientAuthentication_hook = ALL_HOOKS.client_authentication_hook.before_register(
+    Some(auth::pgext_client_authentication_hook),
+    auth::ClientAuthentication_hook,
+  );
+  pgrx::pg_sys::needs_fmgr_hook = ALL_HOOKS
+    .needs_fmgr_hook
+    .before_register(Some(fmgr::pgext_needs_fmgr_hook), pgrx::pg_sys::needs_fmgr_hook);
+  pgrx::pg_sys::fmgr_hook = ALL_HOOKS
+    .fmgr_hook
+    .before_register(Some(fmgr::pgext_fmgr_hook), pgrx::pg_sys::fmgr_hook);
+  pgrx::pg_sys::explain_get_index_name_hook = ALL_HOOKS.explain_get_index_name_hook.before_register(
+    Some(explain::pgext_explain_get_index_name_hook),
+    pgrx::pg_sys::explain_get_index_name_hook,
+  );
+  pgrx::pg_sys::set_rel_pathlist_hook = ALL_HOOKS.set_rel_pathlist_hook.before_register(
+    Some(custom_scan::pgext_set_rel_pathlist_hook),
+    pgrx::pg_sys::set_rel_pathlist_hook,
+  );
+  plpgsql::before_init();
+  let api = Box::leak(Box::new(api::PgExtApi::new(plugin_name.clone())));
+  INSTALLED_PLUGIN_APIS.insert(plugin_name, api);
+  api
 }
 
 #[pg_guard]
 #[no_mangle]
 pub unsafe extern "C" fn __pgext_after_init() {
   let p = INSTALLED_PLUGINS.last().unwrap().clone();
-  pgrx::pg_sys::planner_hook = ALL_HOOKS
-    .planner_hook
-    .after_register(p.clone(), pgrx::pg_sys::planner_hook);
-  pgrx::pg_sys::ExecutorStart_hook = ALL_HOOKS
-    .executor_start_hook
-    .after_register(p.clone(), pgrx::pg_sys::ExecutorStart_hook);
-  pgrx::pg_sys::ExecutorRun_hook = ALL_HOOKS
-    .executor_run_hook
-    .after_register(p.clone(), pgrx::pg_sys::ExecutorRun_hook);
-  pgrx::pg_sys::ExecutorFinish_hook = ALL_HOOKS
-    .executor_finish_hook
-    .after_register(p.clone(), pgrx::pg_sys::ExecutorFinish_hook);
-  pgrx::pg_sys::ExecutorEnd_hook = ALL_HOOKS
-    .executor_end_hook
-    .after_register(p, pgrx::pg_sys::ExecutorEnd_hook);
+  ALL_HOOKS.chained.after_init(&p);
+  pgrx::pg_sys::emit_log_hook = ALL_HOOKS
+    .emit_log_hook
+    .after_register(p.clone(), pgrx::pg_sys::emit_log_hook);
+  pgrx::pg_sys::object_access_hook = ALL_HOOKS
+    .object_access_hook
+    .after_register(p.clone(), pgrx::pg_sys::object_access_hook);
+  pgrx::pg_sys::ExecutorCheckPerms_hook = ALL_HOOKS
+    .executor_check_perms_hook
+    .after_register(p.clone(), pgrx::pg_sys::ExecutorCheckPerms_hook);
+  pgrx::pg_sys::row_security_policy_hook_permissive = ALL_HOOKS
+    .row_security_policy_hook_permissive
+    .after_register(p.clone(), pgrx::pg_sys::row_security_policy_hook_permissive);
+  pgrx::pg_sys::row_security_policy_hook_restrictive = ALL_HOOKS
+    .row_security_policy_hook_restrictive
+    .after_register(p.clone(), pgrx::pg_sys::row_security_policy_hook_restrictive);
+  pgrx::pg_sys::check_password_hook = ALL_HOOKS
+    .check_password_hook
+    .after_register(p.clone(), pgrx::pg_sys::check_password_hook);
+  auth::ClientAuthentication_hook = ALL_HOOKS
+    .client_authentication_hook
+    .after_register(p.clone(), auth::ClientAuthentication_hook);
+  pgrx::pg_sys::needs_fmgr_hook = ALL_HOOKS
+    .needs_fmgr_hook
+    .after_register(p.clone(), pgrx::pg_sys::needs_fmgr_hook);
+  pgrx::pg_sys::fmgr_hook = ALL_HOOKS.fmgr_hook.after_register(p.clone(), pgrx::pg_sys::fmgr_hook);
+  pgrx::pg_sys::explain_get_index_name_hook = ALL_HOOKS
+    .explain_get_index_name_hook
+    .after_register(p.clone(), pgrx::pg_sys::explain_get_index_name_hook);
+  pgrx::pg_sys::set_rel_pathlist_hook = ALL_HOOKS
+    .set_rel_pathlist_hook
+    .after_register(p.clone(), pgrx::pg_sys::set_rel_pathlist_hook);
+  plpgsql::after_init(&p);
+  guc::after_init(&p);
+  timeout::after_init(&p);
 }
 
 #[pg_extern]
@@ -86,8 +153,14 @@ fn all() -> TableIterator<'static, (name!(order, i64), name!(plugin, String), na
 fn change_status(extension: &str, status: bool) -> i64 {
   unsafe {
     if let Some(enabled) = INSTALLED_PLUGINS_STATUS.get_mut(extension) {
+      if *enabled && !status {
+        guc::report_inactive_settings(extension);
+      }
+      if *enabled != status {
+        bgworker::change_status(extension, status);
+      }
       *enabled = status;
-      ALL_HOOKS.rewriters.iter_mut().for_each(|(name, _, enabled)| {
+      ALL_HOOKS.rewriters.iter_mut().for_each(|(name, _, enabled, _)| {
         if name == extension {
           *enabled = status;
         }
@@ -102,10 +175,16 @@ fn change_status(extension: &str, status: bool) -> i64 {
 #[pg_guard]
 fn change_status_all(status: bool) -> i64 {
   unsafe {
-    INSTALLED_PLUGINS_STATUS.iter_mut().for_each(|(_, enabled)| {
+    INSTALLED_PLUGINS_STATUS.iter_mut().for_each(|(name, enabled)| {
+      if *enabled && !status {
+        guc::report_inactive_settings(name);
+      }
+      if *enabled != status {
+        bgworker::change_status(name, status);
+      }
       *enabled = status;
     });
-    ALL_HOOKS.rewriters.iter_mut().for_each(|(_, _, enabled)| {
+    ALL_HOOKS.rewriters.iter_mut().for_each(|(_, _, enabled, _)| {
       *enabled = status;
     });
     INSTALLED_PLUGINS_STATUS.len() as i64
@@ -132,70 +211,593 @@ fn disable_all() -> i64 {
   change_status_all(false)
 }
 
+/// Run the output rewriters of the plugin in `active` or `shadow` mode.
+#[pg_extern]
+fn set_rewriter_mode(extension: &str, mode: &str) -> i64 {
+  let Some(mode) = RewriterMode::parse(mode) else {
+    error!("invalid rewriter mode {}, expected active or shadow", mode)
+  };
+  unsafe {
+    if !INSTALLED_PLUGINS_STATUS.contains_key(extension) {
+      panic!("extension {} does not exist", extension)
+    }
+    let mut count = 0;
+    ALL_HOOKS.rewriters.iter_mut().for_each(|(name, _, _, rewriter_mode)| {
+      if name == extension {
+        *rewriter_mode = mode;
+        count += 1;
+      }
+    });
+    count
+  }
+}
+
+/// Remove all hooks and rewriters of the plugin and free its `PgExtApi`. The
+/// plugin must not call into the api afterwards. As this also removes its
+/// security hooks, it is restricted to superusers.
+#[pg_extern]
+fn unregister(extension: &str) -> i64 {
+  unsafe {
+    if !pg_sys::superuser() {
+      ereport!(
+        ERROR,
+        PgSqlErrorCode::ERRCODE_INSUFFICIENT_PRIVILEGE,
+        "must be superuser to unregister a plugin"
+      );
+    }
+    if extension == "__pgext" {
+      error!("cannot unregister pgextmgr itself");
+    }
+    if INSTALLED_PLUGINS_STATUS.remove(extension).is_none() {
+      panic!("extension {} does not exist", extension)
+    }
+    INSTALLED_PLUGINS.retain(|name| name != extension);
+    ALL_HOOKS.unregister(extension);
+    plpgsql::unregister(extension);
+    bgworker::unregister(extension);
+    custom_scan::unregister(extension);
+    guc::unregister(extension);
+    shmem::unregister(extension);
+    timeout::unregister(extension);
+    memory::unregister(extension);
+    output_rewriter::REWRITER_STATS.remove(extension);
+    dependency::DEPENDENCIES.retain(|(plugin, _, _)| plugin != extension);
+    if let Some(api) = INSTALLED_PLUGIN_APIS.remove(extension) {
+      drop(Box::from_raw(api));
+    }
+    1
+  }
+}
+
+extension_sql!(
+  "REVOKE EXECUTE ON FUNCTION unregister(text) FROM PUBLIC;",
+  name = "revoke_unregister",
+  requires = [unregister]
+);
+
 #[pg_extern]
 fn hooks() -> TableIterator<'static, (name!(hook, String), name!(order, i64), name!(plugin, String))> {
   let mut data = vec![];
   unsafe {
+    data.extend(ALL_HOOKS.chained.registered());
     data.extend(
       ALL_HOOKS
-        .planner_hook
-        .hooks()
+        .rewriters
         .iter()
         .enumerate()
-        .map(|(id, (name, _))| ("planner_hook".to_string(), id as i64, name.clone())),
+        .map(|(id, (name, _, _, _))| ("pgext_rewriters".to_string(), id as i64, name.clone())),
     );
     data.extend(
       ALL_HOOKS
-        .executor_start_hook
-        .hooks()
-        .iter()
+        .emit_log_hook
+        .registered()
         .enumerate()
-        .map(|(id, (name, _))| ("executor_start_hook".to_string(), id as i64, name.clone())),
+        .map(|(id, (name, _, _))| ("emit_log_hook".to_string(), id as i64, name.clone())),
     );
     data.extend(
       ALL_HOOKS
-        .executor_run_hook
-        .hooks()
-        .iter()
+        .needs_fmgr_hook
+        .registered()
         .enumerate()
-        .map(|(id, (name, _))| ("executor_run_hook".to_string(), id as i64, name.clone())),
+        .map(|(id, (name, _, _))| ("needs_fmgr_hook".to_string(), id as i64, name.clone())),
     );
     data.extend(
       ALL_HOOKS
-        .executor_finish_hook
-        .hooks()
-        .iter()
+        .fmgr_hook
+        .registered()
         .enumerate()
-        .map(|(id, (name, _))| ("executor_finish_hook".to_string(), id as i64, name.clone())),
+        .map(|(id, (name, _, _))| ("fmgr_hook".to_string(), id as i64, name.clone())),
     );
     data.extend(
       ALL_HOOKS
-        .executor_end_hook
-        .hooks()
-        .iter()
+        .explain_get_index_name_hook
+        .registered()
         .enumerate()
-        .map(|(id, (name, _))| ("executor_end_hook".to_string(), id as i64, name.clone())),
+        .map(|(id, (name, _, _))| ("explain_get_index_name_hook".to_string(), id as i64, name.clone())),
     );
     data.extend(
       ALL_HOOKS
-        .rewriters
+        .set_rel_pathlist_hook
+        .registered()
+        .enumerate()
+        .map(|(id, (name, _, _))| ("set_rel_pathlist_hook".to_string(), id as i64, name.clone())),
+    );
+    data.extend(
+      ALL_HOOKS
+        .explain_callbacks
+        .registered()
+        .enumerate()
+        .map(|(id, (name, _, _))| ("pgext_explain".to_string(), id as i64, name.clone())),
+    );
+    data.extend(
+      ALL_HOOKS
+        .xact_callbacks
+        .registered()
+        .enumerate()
+        .map(|(id, (name, _, _))| ("xact_callback".to_string(), id as i64, name.clone())),
+    );
+    data.extend(
+      ALL_HOOKS
+        .subxact_callbacks
+        .registered()
+        .enumerate()
+        .map(|(id, (name, _, _))| ("subxact_callback".to_string(), id as i64, name.clone())),
+    );
+    data.extend(
+      plpgsql::PLPGSQL_PLUGINS
         .iter()
+        .filter(|(_, hooks)| hooks.is_some())
         .enumerate()
-        .map(|(id, (name, _, _))| ("pgext_rewriters".to_string(), id as i64, name.clone())),
+        .map(|(id, (name, _))| ("PLpgSQL_plugin".to_string(), id as i64, name.clone())),
     );
   }
   TableIterator::new(data.into_iter())
 }
 
+/// The plugins on each security hook, in the order they are consulted, and
+/// how their results are combined. They are called whether the plugin is
+/// enabled or not.
+#[pg_extern]
+fn security() -> TableIterator<
+  'static,
+  (
+    name!(hook, String),
+    name!(order, i64),
+    name!(plugin, String),
+    name!(combine, String),
+  ),
+> {
+  let mut data = vec![];
+  unsafe {
+    data.extend(
+      ALL_HOOKS
+        .object_access_hook
+        .registered()
+        .enumerate()
+        .map(|(id, (name, _, _))| {
+          (
+            "object_access_hook".to_string(),
+            id as i64,
+            name.clone(),
+            security::OBJECT_ACCESS_COMBINE.to_string(),
+          )
+        }),
+    );
+    data.extend(
+      ALL_HOOKS
+        .executor_check_perms_hook
+        .registered()
+        .enumerate()
+        .map(|(id, (name, _, _))| {
+          (
+            "ExecutorCheckPerms_hook".to_string(),
+            id as i64,
+            name.clone(),
+            security::CHECK_PERMS_COMBINE.to_string(),
+          )
+        }),
+    );
+    data.extend(
+      ALL_HOOKS
+        .row_security_policy_hook_permissive
+        .registered()
+        .enumerate()
+        .map(|(id, (name, _, _))| {
+          (
+            "row_security_policy_hook_permissive".to_string(),
+            id as i64,
+            name.clone(),
+            security::ROW_SECURITY_COMBINE.to_string(),
+          )
+        }),
+    );
+    data.extend(
+      ALL_HOOKS
+        .row_security_policy_hook_restrictive
+        .registered()
+        .enumerate()
+        .map(|(id, (name, _, _))| {
+          (
+            "row_security_policy_hook_restrictive".to_string(),
+            id as i64,
+            name.clone(),
+            security::ROW_SECURITY_COMBINE.to_string(),
+          )
+        }),
+    );
+    data.extend(
+      ALL_HOOKS
+        .check_password_hook
+        .registered()
+        .enumerate()
+        .map(|(id, (name, _, _))| {
+          (
+            "check_password_hook".to_string(),
+            id as i64,
+            name.clone(),
+            security::VOTE_COMBINE.to_string(),
+          )
+        }),
+    );
+    data.extend(
+      ALL_HOOKS
+        .client_authentication_hook
+        .registered()
+        .enumerate()
+        .map(|(id, (name, _, _))| {
+          (
+            "ClientAuthentication_hook".to_string(),
+            id as i64,
+            name.clone(),
+            security::VOTE_COMBINE.to_string(),
+          )
+        }),
+    );
+  }
+  TableIterator::new(data)
+}
+
+/// Shared memory segments and LWLock tranches requested by plugins.
+#[pg_extern]
+fn shmem() -> TableIterator<
+  'static,
+  (
+    name!(plugin, String),
+    name!(name, String),
+    name!(kind, String),
+    name!(size, i64),
+    name!(attached, bool),
+  ),
+> {
+  let mut data = vec![];
+  unsafe {
+    data.extend(shmem::SHMEM_SEGMENTS.iter().map(|segment| {
+      (
+        segment.plugin.clone(),
+        segment.name.clone(),
+        "segment".to_string(),
+        segment.size as i64,
+        !segment.segment.is_null(),
+      )
+    }));
+    data.extend(shmem::LWLOCK_TRANCHES.iter().map(|(plugin, tranche, num_locks)| {
+      (
+        plugin.clone(),
+        tranche.clone(),
+        "lwlocks".to_string(),
+        (*num_locks as usize * std::mem::size_of::<pgrx::pg_sys::LWLockPadded>()) as i64,
+        true,
+      )
+    }));
+  }
+  TableIterator::new(data)
+}
+
+/// Background workers registered by plugins.
+#[pg_extern]
+fn workers() -> TableIterator<
+  'static,
+  (
+    name!(plugin, String),
+    name!(name, String),
+    name!(kind, String),
+    name!(pid, Option<i32>),
+    name!(status, String),
+  ),
+> {
+  TableIterator::new(unsafe {
+    bgworker::WORKERS
+      .iter()
+      .map(|worker| {
+        let (pid, status) = worker.status();
+        (
+          worker.plugin.clone(),
+          worker.name(),
+          worker.kind().to_string(),
+          pid,
+          status,
+        )
+      })
+      .collect::<Vec<_>>()
+  })
+}
+
+/// Custom scans provided by plugins, and how often each was chosen in an
+/// executed plan. The custom paths of disabled plugins are never chosen.
+#[pg_extern]
+fn custom_scans() -> TableIterator<
+  'static,
+  (
+    name!(plugin, String),
+    name!(name, String),
+    name!(status, String),
+    name!(chosen, i64),
+  ),
+> {
+  TableIterator::new(unsafe {
+    custom_scan::CUSTOM_SCANS
+      .iter()
+      .map(|(plugin, name)| {
+        let status = if let Some(&true) = INSTALLED_PLUGINS_STATUS.get(plugin) {
+          "enabled"
+        } else {
+          "disabled"
+        };
+        (
+          plugin.clone(),
+          name.clone(),
+          status.to_string(),
+          custom_scan::CHOSEN.get(name).copied().unwrap_or(0),
+        )
+      })
+      .collect::<Vec<_>>()
+  })
+}
+
+/// Memory allocated by the hooks and output rewriters of each plugin in this
+/// backend, counted in blocks of the contexts they were run in (see `memory`).
+#[pg_extern]
+fn memory() -> TableIterator<
+  'static,
+  (
+    name!(plugin, String),
+    name!(calls, i64),
+    name!(allocated_bytes, i64),
+    name!(max_call_bytes, i64),
+  ),
+> {
+  TableIterator::new(unsafe {
+    memory::MEMORY_STATS
+      .iter()
+      .map(|(plugin, stats)| (plugin.clone(), stats.calls, stats.allocated, stats.max_call))
+      .collect::<Vec<_>>()
+  })
+}
+
+/// Time spent in the hooks and output rewriters of each plugin in this backend,
+/// excluding the rest of the hook chain, and how often a plugin exceeded
+/// `pgextmgr.hook_timeout`.
+#[pg_extern]
+fn stats() -> TableIterator<
+  'static,
+  (
+    name!(plugin, String),
+    name!(calls, i64),
+    name!(total_ms, f64),
+    name!(max_ms, f64),
+    name!(timeouts, i64),
+    name!(skipped, bool),
+  ),
+> {
+  TableIterator::new(unsafe {
+    timeout::TIME_STATS
+      .iter()
+      .map(|(plugin, stats)| {
+        (
+          plugin.clone(),
+          stats.calls,
+          stats.total.as_secs_f64() * 1000.0,
+          stats.max.as_secs_f64() * 1000.0,
+          stats.timeouts,
+          timeout::SKIPPED.contains(plugin),
+        )
+      })
+      .collect::<Vec<_>>()
+  })
+}
+
+/// Rows passed to the output rewriters of each plugin in this backend, and how
+/// many of them a rewriter in shadow mode would have changed or dropped.
+#[pg_extern]
+fn rewriter_stats() -> TableIterator<
+  'static,
+  (
+    name!(plugin, String),
+    name!(mode, String),
+    name!(rows, i64),
+    name!(changed_rows, i64),
+    name!(dropped_rows, i64),
+  ),
+> {
+  TableIterator::new(unsafe {
+    output_rewriter::REWRITER_STATS
+      .iter()
+      .map(|(plugin, stats)| {
+        let mode = ALL_HOOKS
+          .rewriters
+          .iter()
+          .find(|(name, _, _, _)| name == plugin)
+          .map_or(RewriterMode::Active, |(_, _, _, mode)| *mode);
+        (
+          plugin.clone(),
+          mode.as_str().to_string(),
+          stats.rows,
+          stats.changed,
+          stats.dropped,
+        )
+      })
+      .collect::<Vec<_>>()
+  })
+}
+
+/// The plugin callbacks recorded with `pgextmgr.trace` on, oldest first.
+#[pg_extern]
+#[allow(clippy::type_complexity)]
+fn trace() -> TableIterator<
+  'static,
+  (
+    name!(seq, i64),
+    name!(time, TimestampWithTimeZone),
+    name!(hook, String),
+    name!(plugin_id, i64),
+    name!(plugin, String),
+    name!(depth, i32),
+    name!(query_id, Option<i64>),
+    name!(outcome, String),
+    name!(duration_ms, Option<f64>),
+  ),
+> {
+  TableIterator::new(unsafe {
+    trace::TRACE
+      .iter()
+      .map(|entry| {
+        (
+          entry.seq,
+          TimestampWithTimeZone::try_from(entry.time).unwrap(),
+          entry.hook.to_string(),
+          entry.plugin_id,
+          entry.plugin.clone(),
+          entry.depth,
+          (entry.query_id != 0).then_some(entry.query_id as i64),
+          entry.outcome.as_str().to_string(),
+          entry.duration.map(|duration| duration.as_secs_f64() * 1000.0),
+        )
+      })
+      .collect::<Vec<_>>()
+  })
+}
+
+/// Empty the trace, and return the number of entries dropped.
+#[pg_extern]
+fn trace_clear() -> i64 {
+  unsafe { trace::clear() as i64 }
+}
+
+#[pg_extern]
+fn settings() -> TableIterator<
+  'static,
+  (
+    name!(plugin, String),
+    name!(name, String),
+    name!(vartype, String),
+    name!(setting, Option<String>),
+  ),
+> {
+  TableIterator::new(unsafe {
+    guc::PLUGIN_SETTINGS
+      .iter()
+      .map(|(plugin, name, vartype)| {
+        (
+          plugin.clone(),
+          name.clone(),
+          vartype.to_string(),
+          guc::current_value(name),
+        )
+      })
+      .collect::<Vec<_>>()
+      .into_iter()
+  })
+}
+
+/// Run `query` once with only `plugins_a` enabled and once with only
+/// `plugins_b`, each in a subtransaction that is rolled back, and report
+/// whether the row counts, results (compared as sorted text rows) and plans
+/// differ.
+#[pg_extern]
+fn compare(
+  query: &str,
+  plugins_a: Vec<String>,
+  plugins_b: Vec<String>,
+) -> TableIterator<
+  'static,
+  (
+    name!(rows_a, i64),
+    name!(rows_b, i64),
+    name!(results_differ, bool),
+    name!(plans_differ, bool),
+    name!(plan_a, String),
+    name!(plan_b, String),
+  ),
+> {
+  let (a, b) = unsafe { compare::compare(query, &plugins_a, &plugins_b) };
+  TableIterator::once((
+    a.rows as i64,
+    b.rows as i64,
+    a.results != b.results,
+    a.plan != b.plan,
+    a.plan,
+    b.plan,
+  ))
+}
+
+/// The query id of the statement analyzed last (see `query_id`), e.g. the
+/// statement calling this function.
+#[pg_extern]
+fn current_query_id() -> Option<i64> {
+  let query_id = unsafe { query_id::current() };
+  (query_id != 0).then_some(query_id as i64)
+}
+
+/// The normalized text of the statement analyzed last.
+#[pg_extern]
+fn current_normalized_query() -> Option<String> {
+  unsafe {
+    let text = query_id::normalized_query();
+    (!text.is_null()).then(|| std::ffi::CStr::from_ptr(text).to_string_lossy().into_owned())
+  }
+}
+
+#[pg_extern]
+fn dependencies() -> TableIterator<'static, (name!(plugin, String), name!(kind, String), name!(other, String))> {
+  TableIterator::new(unsafe {
+    dependency::DEPENDENCIES
+      .iter()
+      .map(|(plugin, kind, other)| (plugin.clone(), kind.as_str().to_string(), other.clone()))
+      .collect::<Vec<_>>()
+      .into_iter()
+  })
+}
+
 #[no_mangle]
 unsafe extern "C" fn _PG_init() {
+  timeout::init();
+  trace::init();
+  query_id::init();
   __pgext_before_init("__pgext".as_pg_cstr());
-  ALL_HOOKS.executor_run_hook.register(
+  ALL_HOOKS.chained.post_parse_analyze_hook.register(
+    "__pgext".to_string(),
+    Some(crate::query_id::before_post_parse_analyze),
+    Some(crate::query_id::after_post_parse_analyze),
+  );
+  ALL_HOOKS.chained.executor_start_hook.register(
+    "__pgext".to_string(),
+    Some(crate::pgext::before_executor_start),
+    Some(crate::pgext::after_executor_start),
+  );
+  ALL_HOOKS.chained.executor_run_hook.register(
     "__pgext".to_string(),
     Some(crate::pgext::before_executor_run),
     Some(crate::pgext::after_executor_run),
   );
+  ALL_HOOKS.chained.executor_end_hook.register(
+    "__pgext".to_string(),
+    Some(crate::pgext::before_executor_end),
+    Some(crate::pgext::after_executor_end),
+  );
   __pgext_after_init();
+  shmem::init();
+  explain::init();
+  xact::init();
 }
 
 #[cfg(any(test, feature = "pg_test"))]
@@ -300,6 +902,883 @@ pub mod tests {
 
     Ok(())
   }
+
+  #[pg_test]
+  #[search_path(@extschema@)]
+  fn test_plugin_unregister() -> Result<(), spi::Error> {
+    Spi::run("CREATE EXTENSION pgext_pg_poop;")?;
+
+    Spi::connect(|client| {
+      client.select("SELECT pgextmgr.unregister('pgext_pg_poop')", None, None)?;
+
+      let table = client.select("SELECT * FROM pgextmgr.all()", None, None)?;
+      assert_eq!(table.len(), 3);
+      let table = client.select(
+        "SELECT * FROM pgextmgr.hooks() WHERE plugin = 'pgext_pg_poop'",
+        None,
+        None,
+      )?;
+      assert_eq!(table.len(), 0);
+      for function in ["settings", "stats", "memory", "shmem", "rewriter_stats"] {
+        let query = format!("SELECT * FROM pgextmgr.{}() WHERE plugin = 'pgext_pg_poop'", function);
+        assert_eq!(client.select(&query, None, None)?.len(), 0, "{}", function);
+      }
+
+      Ok::<_, pgrx::spi::Error>(())
+    })?;
+
+    Ok(())
+  }
+
+  #[pg_test]
+  fn test_annotation() {
+    unsafe {
+      let value = "/*+ SeqScan(t) */".into_datum().unwrap();
+      crate::annotation::set_annotation("pgext_pg_hint_plan", "hint", pg_sys::TEXTOID, value, false);
+
+      let (value, isnull) = crate::annotation::get_annotation("pgext_pg_hint_plan", "hint", pg_sys::TEXTOID).unwrap();
+      assert!(!isnull);
+      assert_eq!(String::from_datum(value, isnull), Some("/*+ SeqScan(t) */".to_string()));
+      assert!(crate::annotation::get_annotation("pgext_pg_hint_plan", "query_id", pg_sys::INT8OID).is_none());
+      assert!(crate::annotation::get_annotation("pgext_pg_poop", "hint", pg_sys::TEXTOID).is_none());
+    }
+  }
+
+  #[pg_test]
+  fn test_annotation_prepared() -> Result<(), spi::Error> {
+    unsafe {
+      // with the extended protocol, the statement is planned in one message
+      // and executed in the next, and MessageContext is reset in between
+      let message_context = pg_sys::MessageContext;
+      let bind = pgrx::PgMemoryContexts::new("test bind");
+      pg_sys::MessageContext = bind.value();
+      crate::annotation::reset();
+      Spi::run("PREPARE pgext_annotated AS SELECT 1")?;
+      let value = "/*+ SeqScan(t) */".into_datum().unwrap();
+      crate::annotation::set_annotation("pgext_pg_hint_plan", "hint", pg_sys::TEXTOID, value, false);
+      drop(bind);
+      pg_sys::MessageContext = message_context;
+
+      assert_eq!(Spi::get_one::<i32>("EXECUTE pgext_annotated")?, Some(1));
+      let (value, isnull) = crate::annotation::get_annotation("pgext_pg_hint_plan", "hint", pg_sys::TEXTOID).unwrap();
+      assert_eq!(String::from_datum(value, isnull), Some("/*+ SeqScan(t) */".to_string()));
+
+      // once the top-level statement has ended
+      crate::annotation::reset();
+      assert!(crate::annotation::get_annotation("pgext_pg_hint_plan", "hint", pg_sys::TEXTOID).is_none());
+    }
+    Ok(())
+  }
+
+  /// Add a plugin to this backend the way `__pgext_before_init` does, without
+  /// touching any hook, and return its api.
+  unsafe fn test_plugin(name: &str) -> crate::api::PgExtApi {
+    crate::INSTALLED_PLUGINS.push(name.to_string());
+    crate::INSTALLED_PLUGINS_STATUS.insert(name.to_string(), true);
+    crate::api::PgExtApi::new(name.to_string())
+  }
+
+  #[pg_test]
+  fn test_dependencies() -> Result<(), spi::Error> {
+    unsafe {
+      use pgrx::pg_sys::AsPgCStr;
+
+      let a = test_plugin("test_dep_a");
+      let b = test_plugin("test_dep_b");
+      (b.add_dependency)(&b, "test_dep_a".as_pg_cstr());
+      (a.add_dependency)(&a, "test_dep_b".as_pg_cstr());
+      (a.add_dependency)(&a, "test_dep_missing".as_pg_cstr());
+      (b.add_conflict)(&b, "test_dep_a".as_pg_cstr());
+      (b.add_conflict)(&b, "test_dep_missing".as_pg_cstr());
+
+      let violations = crate::dependency::violations()
+        .into_iter()
+        .map(|(level, report)| (level, report.message().to_string()))
+        .collect::<Vec<_>>();
+      assert_eq!(
+        violations,
+        vec![
+          (
+            PgLogLevel::FATAL,
+            "plugin \"test_dep_a\" requires plugin \"test_dep_b\" to be loaded first".to_string()
+          ),
+          (
+            PgLogLevel::FATAL,
+            "plugin \"test_dep_a\" requires plugin \"test_dep_missing\", which is not loaded".to_string()
+          ),
+          (
+            PgLogLevel::WARNING,
+            "plugin \"test_dep_b\" conflicts with plugin \"test_dep_a\"".to_string()
+          ),
+        ]
+      );
+    }
+    assert_eq!(
+      Spi::get_one::<i64>("SELECT count(*) FROM pgextmgr.dependencies() WHERE plugin LIKE 'test_dep_%'")?,
+      Some(5)
+    );
+    Ok(())
+  }
+
+  static mut TEST_GUC_LIMIT: i32 = 0;
+
+  /// Define `test_guc.limit` and reserve the `test_guc` prefix.
+  unsafe fn define_test_guc() {
+    use pgrx::pg_sys::AsPgCStr;
+
+    let api = test_plugin("test_guc");
+    (api.define_int_guc)(
+      &api,
+      "limit".as_pg_cstr(),
+      "Limit of the test plugin.".as_pg_cstr(),
+      std::ptr::null(),
+      std::ptr::addr_of_mut!(TEST_GUC_LIMIT),
+      10,
+      0,
+      100,
+      pg_sys::GucContext_PGC_USERSET,
+      0,
+    );
+    crate::guc::after_init("test_guc");
+  }
+
+  #[pg_test]
+  fn test_plugin_guc() -> Result<(), spi::Error> {
+    unsafe { define_test_guc() };
+    assert_eq!(Spi::get_one::<String>("SHOW test_guc.limit")?.as_deref(), Some("10"));
+    Spi::run("SET test_guc.limit = 20")?;
+    assert_eq!(unsafe { TEST_GUC_LIMIT }, 20);
+    assert_eq!(
+      Spi::get_two::<String, String>(
+        "SELECT vartype, setting FROM pgextmgr.settings() WHERE plugin = 'test_guc' AND name = 'test_guc.limit'"
+      )?,
+      (Some("integer".to_string()), Some("20".to_string()))
+    );
+    Ok(())
+  }
+
+  #[cfg(feature = "pg15")]
+  #[pg_test(error = "invalid configuration parameter name \"test_guc.limt\"")]
+  fn test_plugin_guc_reserved_prefix() -> Result<(), spi::Error> {
+    unsafe { define_test_guc() };
+    Spi::run("SET test_guc.limt = 20")
+  }
+
+  /// (plugin_info, callback) of the PL/pgSQL plugins of `test_plpgsql_plugins`.
+  static mut PLPGSQL_CALLS: Vec<(usize, &'static str)> = Vec::new();
+
+  unsafe extern "C" fn record_func_beg(estate: *mut pg_sys::PLpgSQL_execstate, _func: *mut pg_sys::PLpgSQL_function) {
+    PLPGSQL_CALLS.push(((*estate).plugin_info as usize, "func_beg"));
+  }
+
+  unsafe extern "C" fn record_func_end(estate: *mut pg_sys::PLpgSQL_execstate, _func: *mut pg_sys::PLpgSQL_function) {
+    PLPGSQL_CALLS.push(((*estate).plugin_info as usize, "func_end"));
+  }
+
+  macro_rules! test_plpgsql_plugin {
+    ($info:literal) => {{
+      unsafe extern "C" fn func_setup(estate: *mut pg_sys::PLpgSQL_execstate, _func: *mut pg_sys::PLpgSQL_function) {
+        (*estate).plugin_info = $info as *mut std::ffi::c_void;
+      }
+      pg_sys::PLpgSQL_plugin {
+        func_setup: Some(func_setup),
+        func_beg: Some(record_func_beg),
+        func_end: Some(record_func_end),
+        stmt_beg: None,
+        stmt_end: None,
+        error_callback: None,
+        assign_expr: None,
+        #[cfg(feature = "pg15")]
+        assign_value: None,
+        #[cfg(feature = "pg15")]
+        eval_datum: None,
+        #[cfg(feature = "pg15")]
+        cast_value: None,
+      }
+    }};
+  }
+
+  static mut TEST_PLPGSQL_A: pg_sys::PLpgSQL_plugin = test_plpgsql_plugin!(101usize);
+  static mut TEST_PLPGSQL_B: pg_sys::PLpgSQL_plugin = test_plpgsql_plugin!(102usize);
+  static mut TEST_PLPGSQL_C: pg_sys::PLpgSQL_plugin = test_plpgsql_plugin!(103usize);
+
+  #[pg_test]
+  fn test_plpgsql_plugins() -> Result<(), spi::Error> {
+    unsafe {
+      for (name, plugin) in [
+        ("test_plpgsql_a", std::ptr::addr_of_mut!(TEST_PLPGSQL_A)),
+        ("test_plpgsql_b", std::ptr::addr_of_mut!(TEST_PLPGSQL_B)),
+        ("test_plpgsql_c", std::ptr::addr_of_mut!(TEST_PLPGSQL_C)),
+      ] {
+        let api = test_plugin(name);
+        (api.register_plpgsql_plugin)(&api, plugin);
+      }
+      crate::INSTALLED_PLUGINS_STATUS.insert("test_plpgsql_c".to_string(), false);
+    }
+    Spi::run("DO $$ BEGIN PERFORM 1; END $$")?;
+    // every plugin keeps its own plugin_info, and a disabled plugin is not called
+    assert_eq!(
+      unsafe { PLPGSQL_CALLS.clone() },
+      vec![
+        (101, "func_beg"),
+        (102, "func_beg"),
+        (101, "func_end"),
+        (102, "func_end")
+      ]
+    );
+    Ok(())
+  }
+
+  #[pg_guard]
+  unsafe extern "C" fn failing_emit_log_hook(edata: *mut pg_sys::ErrorData) {
+    if std::ffi::CStr::from_ptr((*edata).message).to_bytes() == b"pgext test log" {
+      error!("boom");
+    }
+  }
+
+  static mut LOGGED: Vec<String> = Vec::new();
+
+  #[pg_guard]
+  unsafe extern "C" fn recording_emit_log_hook(edata: *mut pg_sys::ErrorData) {
+    LOGGED.push(format!(
+      "{} {}",
+      (*edata).elevel,
+      std::ffi::CStr::from_ptr((*edata).message).to_string_lossy()
+    ));
+  }
+
+  #[pg_test]
+  fn test_emit_log_failure() -> Result<(), spi::Error> {
+    unsafe {
+      for (name, hook) in [
+        (
+          "test_emit_log_failing",
+          failing_emit_log_hook as unsafe extern "C" fn(*mut pg_sys::ErrorData),
+        ),
+        ("test_emit_log_recording", recording_emit_log_hook),
+      ] {
+        let api = test_plugin(name);
+        (api.register_emit_log_hook)(&api, Some(hook), pg_sys::NOTICE as std::ffi::c_int, std::ptr::null(), 0);
+      }
+      let prev_hook = pg_sys::emit_log_hook;
+      pg_sys::emit_log_hook = Some(crate::emit_log::pgext_emit_log_hook);
+      let result = Spi::run("DO $$ BEGIN RAISE NOTICE 'pgext test log'; END $$");
+      pg_sys::emit_log_hook = prev_hook;
+      result?;
+      assert_eq!(LOGGED.clone(), vec![format!("{} pgext test log", pg_sys::NOTICE)]);
+    }
+    Ok(())
+  }
+
+  unsafe extern "C" fn deny_all(_range_tables: *mut pg_sys::List, _abort: bool) -> bool {
+    false
+  }
+
+  #[pg_test]
+  fn test_security_hooks_ignore_status() -> Result<(), spi::Error> {
+    unsafe {
+      test_plugin("test_security");
+      crate::hook_mgr::ALL_HOOKS
+        .executor_check_perms_hook
+        .register("test_security".to_string(), Some(deny_all), ());
+      Spi::run("SELECT pgextmgr.disable('test_security')")?;
+      assert!(!crate::security::pgext_executor_check_perms_hook(
+        std::ptr::null_mut(),
+        false
+      ));
+      Spi::run("SELECT pgextmgr.unregister('test_security')")?;
+      assert!(crate::security::pgext_executor_check_perms_hook(
+        std::ptr::null_mut(),
+        false
+      ));
+    }
+    assert_eq!(
+      Spi::get_one::<bool>("SELECT has_function_privilege('public', 'pgextmgr.unregister(text)', 'EXECUTE')")?,
+      Some(false)
+    );
+    Ok(())
+  }
+
+  #[pg_guard]
+  unsafe extern "C" fn reject_short(
+    _username: *const std::ffi::c_char,
+    _shadow_pass: *const std::ffi::c_char,
+    _password_type: pg_sys::PasswordType,
+    _validuntil_time: pg_sys::Datum,
+    _validuntil_null: bool,
+  ) {
+    error!("too short");
+  }
+
+  #[pg_guard]
+  unsafe extern "C" fn reject_common(
+    _username: *const std::ffi::c_char,
+    _shadow_pass: *const std::ffi::c_char,
+    _password_type: pg_sys::PasswordType,
+    _validuntil_time: pg_sys::Datum,
+    _validuntil_null: bool,
+  ) {
+    error!("too common");
+  }
+
+  #[pg_test]
+  fn test_check_password_votes() {
+    unsafe {
+      use pgrx::pg_sys::AsPgCStr;
+
+      for (plugin, hook) in [
+        ("test_password_a", reject_short as _),
+        ("test_password_b", reject_common as _),
+      ] {
+        test_plugin(plugin);
+        crate::hook_mgr::ALL_HOOKS
+          .check_password_hook
+          .register(plugin.to_string(), Some(hook), ());
+      }
+      let error = crate::callback::catch_error(|| {
+        crate::auth::pgext_check_password_hook(
+          "alice".as_pg_cstr(),
+          "secret".as_pg_cstr(),
+          pg_sys::PasswordType_PASSWORD_TYPE_PLAINTEXT,
+          pg_sys::Datum::from(0),
+          true,
+        )
+      })
+      .unwrap_err();
+      assert_eq!(error.message(), "password rejected");
+      assert_eq!(
+        error.detail(),
+        Some("test_password_a: too short\ntest_password_b: too common")
+      );
+    }
+  }
+
+  #[pg_test]
+  fn test_shmem_requests() -> Result<(), spi::Error> {
+    unsafe {
+      use pgrx::pg_sys::AsPgCStr;
+
+      let api = test_plugin("test_shmem");
+      pg_sys::process_shared_preload_libraries_in_progress = true;
+      (api.request_shmem)(&api, "state".as_pg_cstr(), 1024, None);
+      (api.request_lwlocks)(&api, "locks".as_pg_cstr(), 2);
+      pg_sys::process_shared_preload_libraries_in_progress = false;
+    }
+    let rows = Spi::connect(|client| {
+      client
+        .select(
+          "SELECT name, kind, size, attached FROM pgextmgr.shmem() WHERE plugin = 'test_shmem' ORDER BY kind",
+          None,
+          None,
+        )?
+        .map(|row| {
+          Ok((
+            row.get_by_name::<String, _>("name")?.unwrap(),
+            row.get_by_name::<String, _>("kind")?.unwrap(),
+            row.get_by_name::<i64, _>("size")?.unwrap(),
+            row.get_by_name::<bool, _>("attached")?.unwrap(),
+          ))
+        })
+        .collect::<Result<Vec<_>, spi::Error>>()
+    })?;
+    let lwlocks_size = 2 * std::mem::size_of::<pg_sys::LWLockPadded>() as i64;
+    assert_eq!(
+      rows,
+      vec![
+        (
+          "test_shmem.locks".to_string(),
+          "lwlocks".to_string(),
+          lwlocks_size,
+          true
+        ),
+        ("test_shmem.state".to_string(), "segment".to_string(), 1024, false),
+      ]
+    );
+    Ok(())
+  }
+
+  #[pg_test(error = "shared memory can only be requested from _PG_init of a preloaded plugin")]
+  fn test_shmem_request_after_startup() {
+    unsafe {
+      use pgrx::pg_sys::AsPgCStr;
+
+      let api = test_plugin("test_shmem");
+      (api.request_shmem)(&api, "state".as_pg_cstr(), 1024, None);
+    }
+  }
+
+  static mut TEST_FMGR_OID: pg_sys::Oid = pg_sys::InvalidOid;
+  static mut FMGR_CALLS: Vec<(&str, pg_sys::FmgrHookEventType, usize)> = vec![];
+
+  unsafe extern "C" fn needs_test_fmgr_oid(fn_oid: pg_sys::Oid) -> bool {
+    fn_oid == TEST_FMGR_OID
+  }
+
+  macro_rules! test_fmgr_hook {
+    ($plugin:literal, $datum:literal) => {{
+      unsafe extern "C" fn hook(
+        event: pg_sys::FmgrHookEventType,
+        _flinfo: *mut pg_sys::FmgrInfo,
+        arg: *mut pg_sys::Datum,
+      ) {
+        if event == pg_sys::FmgrHookEventType_FHET_START {
+          *arg = pg_sys::Datum::from($datum as usize);
+        }
+        FMGR_CALLS.push(($plugin, event, (*arg).value()));
+      }
+      hook
+    }};
+  }
+
+  #[pg_test]
+  fn test_fmgr_hooks() -> Result<(), spi::Error> {
+    let fn_oid = Spi::get_one::<pg_sys::Oid>("SELECT 'lower(text)'::regprocedure::oid")?.unwrap();
+    unsafe {
+      TEST_FMGR_OID = fn_oid;
+      for (plugin, needs, hook) in [
+        ("test_fmgr_a", true, test_fmgr_hook!("test_fmgr_a", 201) as _),
+        ("test_fmgr_b", true, test_fmgr_hook!("test_fmgr_b", 202) as _),
+        ("test_fmgr_c", false, test_fmgr_hook!("test_fmgr_c", 203) as _),
+      ] {
+        test_plugin(plugin);
+        if needs {
+          crate::hook_mgr::ALL_HOOKS
+            .needs_fmgr_hook
+            .register(plugin.to_string(), Some(needs_test_fmgr_oid), ());
+        }
+        crate::hook_mgr::ALL_HOOKS
+          .fmgr_hook
+          .register(plugin.to_string(), Some(hook), ());
+      }
+      Spi::run("SELECT pgextmgr.disable('test_fmgr_b')")?;
+      assert!(crate::fmgr::pgext_needs_fmgr_hook(fn_oid));
+
+      let mut flinfo = pg_sys::FmgrInfo {
+        fn_oid,
+        fn_mcxt: pg_sys::CurrentMemoryContext,
+        ..Default::default()
+      };
+      let mut arg = pg_sys::Datum::from(0);
+      crate::fmgr::pgext_fmgr_hook(pg_sys::FmgrHookEventType_FHET_START, &mut flinfo, &mut arg);
+      crate::fmgr::pgext_fmgr_hook(pg_sys::FmgrHookEventType_FHET_END, &mut flinfo, &mut arg);
+      assert_eq!(
+        FMGR_CALLS,
+        vec![
+          ("test_fmgr_a", pg_sys::FmgrHookEventType_FHET_START, 201),
+          ("test_fmgr_b", pg_sys::FmgrHookEventType_FHET_START, 202),
+          ("test_fmgr_b", pg_sys::FmgrHookEventType_FHET_END, 202),
+          ("test_fmgr_a", pg_sys::FmgrHookEventType_FHET_END, 201),
+        ]
+      );
+    }
+    Ok(())
+  }
+
+  unsafe extern "C" fn explain_answer(_query_desc: *mut pg_sys::QueryDesc, es: *mut pg_sys::ExplainState) {
+    use pgrx::pg_sys::AsPgCStr;
+
+    pg_sys::ExplainPropertyText("Answer".as_pg_cstr(), "42".as_pg_cstr(), es);
+  }
+
+  #[pg_test]
+  fn test_explain_section() -> Result<(), spi::Error> {
+    unsafe {
+      let api = test_plugin("test_explain");
+      (api.register_explain_callback)(&api, Some(explain_answer));
+    }
+    let plan = Spi::get_one::<pgrx::Json>("EXPLAIN (FORMAT JSON) SELECT 1")?.unwrap();
+    assert_eq!(plan.0[0]["pgext"]["test_explain"]["Answer"], "42");
+
+    Spi::run("SELECT pgextmgr.disable('test_explain')")?;
+    let plan = Spi::get_one::<pgrx::Json>("EXPLAIN (FORMAT JSON) SELECT 1")?.unwrap();
+    assert!(plan.0[0].get("pgext").is_none());
+    Ok(())
+  }
+
+  unsafe extern "C" fn no_index_name(_index_id: pg_sys::Oid) -> *const std::ffi::c_char {
+    std::ptr::null()
+  }
+
+  unsafe extern "C" fn index_name_b(_index_id: pg_sys::Oid) -> *const std::ffi::c_char {
+    pg_sys::AsPgCStr::as_pg_cstr("index_b")
+  }
+
+  unsafe extern "C" fn index_name_c(_index_id: pg_sys::Oid) -> *const std::ffi::c_char {
+    pg_sys::AsPgCStr::as_pg_cstr("index_c")
+  }
+
+  #[pg_test]
+  fn test_explain_index_name() -> Result<(), spi::Error> {
+    let index_name = || unsafe {
+      let name = crate::explain::pgext_explain_get_index_name_hook(pg_sys::InvalidOid);
+      (!name.is_null()).then(|| std::ffi::CStr::from_ptr(name).to_str().unwrap())
+    };
+    unsafe {
+      for (plugin, hook) in [
+        ("test_index_name_a", no_index_name as _),
+        ("test_index_name_b", index_name_b as _),
+        ("test_index_name_c", index_name_c as _),
+      ] {
+        test_plugin(plugin);
+        crate::hook_mgr::ALL_HOOKS
+          .explain_get_index_name_hook
+          .register(plugin.to_string(), Some(hook), ());
+      }
+    }
+    assert_eq!(index_name(), Some("index_b"));
+    Spi::run("SELECT pgextmgr.disable('test_index_name_b')")?;
+    assert_eq!(index_name(), Some("index_c"));
+    Spi::run("SELECT pgextmgr.disable('test_index_name_c')")?;
+    assert_eq!(index_name(), None);
+    Ok(())
+  }
+
+  static mut SUBXACT_EVENTS: Vec<(usize, pg_sys::SubXactEvent)> = vec![];
+
+  unsafe extern "C" fn record_subxact_event(
+    event: pg_sys::SubXactEvent,
+    _my_subid: pg_sys::SubTransactionId,
+    _parent_subid: pg_sys::SubTransactionId,
+    arg: *mut std::ffi::c_void,
+  ) {
+    SUBXACT_EVENTS.push((arg as usize, event));
+  }
+
+  #[pg_test]
+  fn test_subxact_callbacks() -> Result<(), spi::Error> {
+    unsafe {
+      for (plugin, arg) in [
+        ("test_subxact_a", 301),
+        ("test_subxact_b", 302),
+        ("test_subxact_c", 303),
+      ] {
+        let api = test_plugin(plugin);
+        (api.register_subxact_callback)(&api, Some(record_subxact_event), arg as *mut std::ffi::c_void);
+      }
+    }
+    Spi::run("SELECT pgextmgr.disable('test_subxact_b')")?;
+    Spi::run("DO $$ BEGIN BEGIN PERFORM 1; EXCEPTION WHEN others THEN NULL; END; END $$")?;
+    let events = unsafe {
+      SUBXACT_EVENTS
+        .iter()
+        .copied()
+        .filter(|(_, event)| {
+          [
+            pg_sys::SubXactEvent_SUBXACT_EVENT_START_SUB,
+            pg_sys::SubXactEvent_SUBXACT_EVENT_COMMIT_SUB,
+          ]
+          .contains(event)
+        })
+        .collect::<Vec<_>>()
+    };
+    assert_eq!(
+      events,
+      vec![
+        (301, pg_sys::SubXactEvent_SUBXACT_EVENT_START_SUB),
+        (303, pg_sys::SubXactEvent_SUBXACT_EVENT_START_SUB),
+        (301, pg_sys::SubXactEvent_SUBXACT_EVENT_COMMIT_SUB),
+        (303, pg_sys::SubXactEvent_SUBXACT_EVENT_COMMIT_SUB),
+      ]
+    );
+    Ok(())
+  }
+
+  #[pg_guard]
+  #[no_mangle]
+  pub extern "C" fn pgext_test_worker(_arg: pg_sys::Datum) {}
+
+  fn copy_cstr(field: &mut [std::ffi::c_char], value: &str) {
+    for (c, b) in field.iter_mut().zip(value.bytes().chain([0])) {
+      *c = b as std::ffi::c_char;
+    }
+  }
+
+  #[pg_test]
+  fn test_workers() -> Result<(), spi::Error> {
+    unsafe {
+      let api = test_plugin("test_worker");
+      let mut worker = pg_sys::BackgroundWorker {
+        bgw_flags: pg_sys::BGWORKER_SHMEM_ACCESS as _,
+        bgw_start_time: pg_sys::BgWorkerStartTime_BgWorkerStart_RecoveryFinished,
+        bgw_restart_time: pg_sys::BGW_NEVER_RESTART as _,
+        ..Default::default()
+      };
+      copy_cstr(&mut worker.bgw_name, "pgext test worker");
+      copy_cstr(&mut worker.bgw_type, "pgext test worker");
+      copy_cstr(&mut worker.bgw_library_name, "pgextmgr");
+      copy_cstr(&mut worker.bgw_function_name, "pgext_test_worker");
+      assert!(!(api.register_dynamic_bgworker)(&api, &mut worker).is_null());
+    }
+    assert_eq!(
+      Spi::get_one::<String>("SELECT name || ' ' || kind FROM pgextmgr.workers() WHERE plugin = 'test_worker'")?,
+      Some("pgext test worker dynamic".to_string())
+    );
+    let status = || Spi::get_one::<String>("SELECT status FROM pgextmgr.workers() WHERE plugin = 'test_worker'");
+    assert_ne!(status()?, Some("stopped (disabled)".to_string()));
+    Spi::run("SELECT pgextmgr.disable('test_worker')")?;
+    assert_eq!(status()?, Some("stopped (disabled)".to_string()));
+    Spi::run("SELECT pgextmgr.enable('test_worker')")?;
+    assert_ne!(status()?, Some("stopped (disabled)".to_string()));
+    Ok(())
+  }
+
+  #[pg_test]
+  fn test_custom_scans() -> Result<(), spi::Error> {
+    let status = || Spi::get_one::<String>("SELECT status FROM pgextmgr.custom_scans() WHERE name = 'TestScan'");
+    let (methods, path) = unsafe {
+      let api = test_plugin("test_custom_scan");
+      let methods = Box::leak(Box::new(pg_sys::CustomPathMethods {
+        CustomName: std::ffi::CString::new("TestScan").unwrap().into_raw(),
+        ..Default::default()
+      }));
+      (api.register_custom_scan)(&api, methods);
+      let path = pg_sys::palloc0(std::mem::size_of::<pg_sys::Path>()) as *mut pg_sys::Path;
+      (*path).type_ = pg_sys::NodeTag_T_Path;
+      (methods as *const pg_sys::CustomPathMethods, path)
+    };
+    let paths = || unsafe {
+      let custom = pg_sys::palloc0(std::mem::size_of::<pg_sys::CustomPath>()) as *mut pg_sys::CustomPath;
+      (*custom).path.type_ = pg_sys::NodeTag_T_CustomPath;
+      (*custom).methods = methods;
+      let mut list = pgrx::PgList::<pg_sys::Path>::new();
+      list.push(path);
+      list.push(custom as *mut pg_sys::Path);
+      pgrx::PgList::<pg_sys::Path>::from_pg(crate::custom_scan::filter_paths(list.into_pg())).len()
+    };
+
+    assert_eq!(status()?, Some("enabled".to_string()));
+    assert_eq!(paths(), 2);
+    Spi::run("SELECT pgextmgr.disable('test_custom_scan')")?;
+    assert_eq!(status()?, Some("disabled".to_string()));
+    assert_eq!(paths(), 1);
+    assert_eq!(
+      Spi::get_one::<i64>("SELECT chosen FROM pgextmgr.custom_scans() WHERE name = 'TestScan'")?,
+      Some(0)
+    );
+    Ok(())
+  }
+
+  #[pg_test]
+  fn test_memory_accounting() {
+    unsafe {
+      let caller = pg_sys::CurrentMemoryContext;
+      let context = crate::memory::run("test_memory", || {
+        assert_ne!(pg_sys::CurrentMemoryContext, caller);
+        crate::memory::outside(|| assert_eq!(pg_sys::CurrentMemoryContext, caller));
+        pg_sys::palloc(10000);
+        pg_sys::CurrentMemoryContext
+      });
+      crate::memory::run("test_memory", || assert_eq!(pg_sys::CurrentMemoryContext, context));
+      assert_eq!(pg_sys::CurrentMemoryContext, caller);
+
+      let stats = crate::memory::MEMORY_STATS.get("test_memory").unwrap();
+      assert_eq!(stats.calls, 2);
+      assert!(stats.allocated >= 10000);
+      assert_eq!(stats.allocated, stats.max_call);
+    }
+  }
+
+  #[pg_test]
+  fn test_hook_timeout() -> Result<(), spi::Error> {
+    Spi::run("SET pgextmgr.hook_timeout = 5")?;
+    Spi::run("SET pgextmgr.hook_timeout_skip = on")?;
+    unsafe {
+      let sleep = || std::thread::sleep(std::time::Duration::from_millis(10));
+      crate::timeout::run("test_timeout", || crate::timeout::outside(sleep));
+      assert!(!crate::timeout::is_skipped("test_timeout"));
+      crate::timeout::run("test_timeout", sleep);
+      assert!(crate::timeout::is_skipped("test_timeout"));
+
+      let stats = crate::timeout::TIME_STATS.get("test_timeout").unwrap();
+      assert_eq!(stats.calls, 2);
+      assert_eq!(stats.timeouts, 1);
+      crate::timeout::SKIPPED.clear();
+    }
+    Ok(())
+  }
+
+  #[pg_test]
+  fn test_hook_timeout_subxact_abort() {
+    unsafe {
+      let result = crate::callback::catch_error::<()>(|| crate::timeout::run("test_timeout", || error!("boom")));
+      assert!(result.is_err());
+      assert_eq!(crate::timeout::FRAMES.len(), 0);
+    }
+  }
+
+  #[pg_test]
+  fn test_rewriter_slot() {
+    unsafe {
+      use pgrx::pg_sys::AsPgCStr;
+
+      let tupdesc = pg_sys::CreateTemplateTupleDesc(2);
+      pg_sys::TupleDescInitEntry(tupdesc, 1, "id".as_pg_cstr(), pg_sys::INT4OID, -1, 0);
+      pg_sys::TupleDescInitEntry(tupdesc, 2, "name".as_pg_cstr(), pg_sys::TEXTOID, -1, 0);
+      let slot = pg_sys::MakeSingleTupleTableSlot(tupdesc, &pg_sys::TTSOpsVirtual);
+      *(*slot).tts_values = 42.into_datum().unwrap();
+      *(*slot).tts_isnull = false;
+      *(*slot).tts_values.add(1) = "pgext".into_datum().unwrap();
+      *(*slot).tts_isnull.add(1) = false;
+      pg_sys::ExecStoreVirtualTuple(slot);
+
+      let mut slot = crate::output_rewriter::RewriterSlot::from_ptr(slot);
+      assert_eq!(slot.attr_index("name"), Some(1));
+      assert_eq!(slot.attr_index("missing"), None);
+      assert_eq!(slot.attrs_of_type(pg_sys::TEXTOID), vec![1]);
+      assert_eq!(slot.get::<i32>("id"), Some(42));
+      slot.set("name", Some("rewritten"));
+      assert_eq!(slot.get::<String>("name"), Some("rewritten".to_string()));
+      slot.set::<i32>("id", None);
+      assert_eq!(slot.get::<i32>("id"), None);
+    }
+  }
+
+  #[pg_test]
+  fn test_shadow_compare() {
+    unsafe {
+      use pgrx::pg_sys::AsPgCStr;
+
+      let tupdesc = pg_sys::CreateTemplateTupleDesc(2);
+      pg_sys::TupleDescInitEntry(tupdesc, 1, "id".as_pg_cstr(), pg_sys::INT8OID, -1, 0);
+      pg_sys::TupleDescInitEntry(tupdesc, 2, "name".as_pg_cstr(), pg_sys::TEXTOID, -1, 0);
+      let slot = pg_sys::MakeSingleTupleTableSlot(tupdesc, &pg_sys::TTSOpsVirtual);
+      *(*slot).tts_values = 42i64.into_datum().unwrap();
+      *(*slot).tts_isnull = false;
+      *(*slot).tts_values.add(1) = "pgext".into_datum().unwrap();
+      *(*slot).tts_isnull.add(1) = false;
+      pg_sys::ExecStoreVirtualTuple(slot);
+
+      let copy = pg_sys::MakeSingleTupleTableSlot(tupdesc, &pg_sys::TTSOpsVirtual);
+      crate::output_rewriter::copy_slot(slot, copy);
+      let mut shadow = crate::output_rewriter::RewriterSlot::from_ptr(copy);
+      shadow.set("name", Some("pgext"));
+      assert!(!crate::output_rewriter::slots_differ(slot, copy));
+      shadow.set("name", Some("rewritten"));
+      assert!(crate::output_rewriter::slots_differ(slot, copy));
+      shadow.set("name", Some("pgext"));
+      shadow.set::<i64>("id", None);
+      assert!(crate::output_rewriter::slots_differ(slot, copy));
+    }
+  }
+
+  #[pg_test]
+  fn test_trace() -> Result<(), spi::Error> {
+    assert!(crate::trace::like(
+      b"%FROM t WHERE _ = 1",
+      b"SELECT * FROM t WHERE a = 1"
+    ));
+    assert!(!crate::trace::like(b"%FROM t", b"SELECT * FROM t WHERE a = 1"));
+    assert!(crate::trace::like(b"100\\%", b"100%"));
+
+    Spi::run("SET pgextmgr.trace = on")?;
+    unsafe {
+      crate::trace::clear();
+      crate::callback::run("test_hook", "test_trace", || {
+        crate::callback::run("test_hook.nested", "test_trace", || ());
+      });
+      crate::callback::skip("test_hook", "test_trace");
+      let entries = crate::trace::TRACE
+        .iter()
+        .map(|entry| (entry.hook, entry.depth, entry.outcome.as_str()))
+        .collect::<Vec<_>>();
+      assert_eq!(
+        entries,
+        vec![
+          ("test_hook", 0, "ok"),
+          ("test_hook.nested", 1, "ok"),
+          ("test_hook", 0, "skipped")
+        ]
+      );
+      assert_eq!(crate::trace::clear(), 3);
+    }
+    Spi::run("RESET pgextmgr.trace")?;
+    Ok(())
+  }
+
+  #[pg_test]
+  fn test_trace_subxact_abort() -> Result<(), spi::Error> {
+    Spi::run("SET pgextmgr.trace = on")?;
+    unsafe {
+      crate::trace::clear();
+      crate::callback::run("test_hook", "test_trace", || {
+        let result = crate::callback::catch_error::<()>(|| {
+          crate::callback::run("test_hook.nested", "test_trace", || error!("boom"))
+        });
+        assert!(result.is_err());
+        crate::callback::run("test_hook.after", "test_trace", || ());
+      });
+      let entries = crate::trace::TRACE
+        .iter()
+        .map(|entry| (entry.hook, entry.depth, entry.outcome.as_str()))
+        .collect::<Vec<_>>();
+      assert_eq!(
+        entries,
+        vec![
+          ("test_hook", 0, "ok"),
+          ("test_hook.nested", 1, "error"),
+          ("test_hook.after", 1, "ok")
+        ]
+      );
+      crate::trace::clear();
+    }
+    Spi::run("RESET pgextmgr.trace")?;
+    Ok(())
+  }
+
+  #[pg_test]
+  #[search_path(@extschema@)]
+  fn test_compare() -> Result<(), spi::Error> {
+    Spi::run("CREATE TABLE compare_t AS SELECT generate_series(1, 3)::text AS x")?;
+    // pgext_pg_poop rewrites the returned text
+    let (rows, results_differ, plans_differ) = Spi::get_three::<bool, bool, bool>(
+      "SELECT rows_a = 2 AND rows_b = 2, results_differ, plans_differ FROM compare('DELETE FROM compare_t WHERE x \
+       > ''1'' RETURNING x', '{}', '{pgext_pg_poop}')",
+    )?;
+    assert_eq!(
+      (rows, results_differ, plans_differ),
+      (Some(true), Some(true), Some(false))
+    );
+    // both runs were rolled back
+    assert_eq!(Spi::get_one::<i64>("SELECT count(*) FROM compare_t")?, Some(3));
+    assert_eq!(
+      Spi::get_one::<String>("SELECT status FROM all() WHERE plugin = 'pgext_pg_poop'")?.as_deref(),
+      Some("enabled")
+    );
+    Ok(())
+  }
+
+  #[pg_test(error = "division by zero")]
+  #[search_path(@extschema@)]
+  fn test_compare_error() -> Result<(), spi::Error> {
+    Spi::run("SELECT * FROM compare('SELECT 1 / 0', '{}', '{pgext_pg_poop}')")
+  }
+
+  #[pg_test]
+  fn test_normalize_query() {
+    let normalize = |text: &str, locations: &[usize], highest_param| {
+      String::from_utf8(crate::query_id::normalize(text.as_bytes(), locations, highest_param)).unwrap()
+    };
+    assert_eq!(
+      normalize("SELECT 'a''b', E'it\\'s' FROM t WHERE x = - 1.5e-3", &[7, 15, 41], 0),
+      "SELECT $1, $2 FROM t WHERE x = $3"
+    );
+    assert_eq!(
+      normalize("SELECT $1 + $$x$$ + $q$y$q$", &[12, 20, 12], 1),
+      "SELECT $1 + $2 + $3"
+    );
+  }
+
+  #[cfg(not(feature = "pg13"))]
+  #[pg_test]
+  #[search_path(@extschema@)]
+  fn test_current_query_id() -> Result<(), spi::Error> {
+    let text = Spi::get_one::<String>("SELECT current_normalized_query() WHERE 1 = 1")?;
+    assert_eq!(text.as_deref(), Some("SELECT current_normalized_query() WHERE $1 = $2"));
+    let id = Spi::get_one::<i64>("SELECT current_query_id() WHERE 2 = 2")?;
+    assert_eq!(id, Spi::get_one::<i64>("SELECT current_query_id() WHERE 3 = 3")?);
+    assert!(id.is_some());
+    Ok(())
+  }
 }
 
 /// This module is required by `cargo pgx test` invocations.
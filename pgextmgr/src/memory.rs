@@ -0,0 +1,105 @@
+//! Per-plugin memory accounting
+//!
+//! Every plugin callback runs in a child of the memory context it is called
+//! in, so that its allocations keep their usual lifetime but can be attributed
+//! to the plugin. Each plugin gets one such child per calling context, created
+//! on its first callback there and reused by the following ones. What the
+//! child grew by during a callback is added to the plugin's totals shown by
+//! `memory()`. These are block totals (`MemoryContextMemAllocated`): they
+//! include the free space in the blocks and the first block of each child, and
+//! a callback that fits in space freed by an earlier one counts as 0. The
+//! context identifier is the plugin name, so that
+//! `pg_log_backend_memory_contexts` also tells plugins apart.
+
+use std::collections::BTreeMap;
+use std::ffi::{c_char, CString};
+
+use pgrx::pg_sys::{self, MemoryContext};
+
+static CONTEXT_NAME: &[u8] = b"pgext plugin\0";
+
+#[derive(Default)]
+pub struct MemoryStats {
+  /// Kept alive for the identifier of the plugin's contexts.
+  ident: CString,
+  pub calls: i64,
+  /// Bytes the plugin's contexts grew by, over all calls.
+  pub allocated: i64,
+  /// Most bytes they grew by in a single call.
+  pub max_call: i64,
+}
+
+/// Memory used by each plugin in this backend.
+pub static mut MEMORY_STATS: BTreeMap<String, MemoryStats> = BTreeMap::new();
+
+unsafe fn is_plugin_context(context: MemoryContext) -> bool {
+  std::ptr::eq((*context).name, CONTEXT_NAME.as_ptr() as *const c_char)
+}
+
+/// The child of `parent` for the plugin with identifier `ident`, if any.
+unsafe fn find_context(parent: MemoryContext, ident: *const c_char) -> Option<MemoryContext> {
+  let mut child = (*parent).firstchild;
+  while !child.is_null() {
+    if is_plugin_context(child) && std::ptr::eq((*child).ident, ident) {
+      return Some(child);
+    }
+    child = (*child).nextchild;
+  }
+  None
+}
+
+/// Run a callback of `plugin` in its child of the current memory context.
+pub unsafe fn run<R>(plugin: &str, f: impl FnOnce() -> R) -> R {
+  let stats = MEMORY_STATS.entry(plugin.to_string()).or_insert_with(|| MemoryStats {
+    ident: CString::new(plugin).unwrap(),
+    ..Default::default()
+  });
+  let caller = pg_sys::CurrentMemoryContext;
+  let (context, before) = match find_context(caller, stats.ident.as_ptr()) {
+    Some(context) => (context, pg_sys::MemoryContextMemAllocated(context, true) as i64),
+    None => {
+      let context = pg_sys::AllocSetContextCreateExtended(
+        caller,
+        CONTEXT_NAME.as_ptr() as *const c_char,
+        pg_sys::ALLOCSET_SMALL_MINSIZE as usize,
+        pg_sys::ALLOCSET_SMALL_INITSIZE as usize,
+        pg_sys::ALLOCSET_SMALL_MAXSIZE as usize,
+      );
+      pg_sys::MemoryContextSetIdentifier(context, stats.ident.as_ptr());
+      (context, 0)
+    }
+  };
+
+  pg_sys::CurrentMemoryContext = context;
+  let result = f();
+  pg_sys::CurrentMemoryContext = caller;
+
+  let bytes = (pg_sys::MemoryContextMemAllocated(context, true) as i64 - before).max(0);
+  let stats = MEMORY_STATS.get_mut(plugin).unwrap();
+  stats.calls += 1;
+  stats.allocated += bytes;
+  stats.max_call = stats.max_call.max(bytes);
+  result
+}
+
+/// Forget the stats of an unregistered plugin. The identifier is leaked, as
+/// the contexts created for the plugin may still refer to it.
+pub unsafe fn unregister(plugin: &str) {
+  if let Some(stats) = MEMORY_STATS.remove(plugin) {
+    let _ = stats.ident.into_raw();
+  }
+}
+
+/// Run `f` in the context the current plugin callback was called in, if we
+/// are in one. Used when a plugin passes control on to the next one or to
+/// PostgreSQL, whose allocations should not count against the plugin.
+pub unsafe fn outside<R>(f: impl FnOnce() -> R) -> R {
+  let current = pg_sys::CurrentMemoryContext;
+  if !is_plugin_context(current) {
+    return f();
+  }
+  pg_sys::CurrentMemoryContext = (*current).parent;
+  let result = f();
+  pg_sys::CurrentMemoryContext = current;
+  result
+}
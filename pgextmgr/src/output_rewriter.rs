@@ -1,29 +1,144 @@
-use std::ffi::c_int;
+//! Output rewriters, which see every row sent to the client
+//!
+//! The rewriters of enabled plugins are chained in front of the query's
+//! `DestReceiver`. Each row is first copied into a virtual slot, so that
+//! rewriters can replace values in `tts_values` whatever kind of slot the
+//! executor produced, and the chain runs in a memory context that is reset
+//! after the row has been sent. Each rewriter callback runs through
+//! `callback::run`, like plugin hooks. `RewriterSlot` gives typed access to the
+//! values by name or type, and is also exposed to C through `PgExtApi`.
+//!
+//! A rewriter in shadow mode gets a copy of the row instead. Once it passes
+//! the row on, its copy is compared with the original and discarded, and the
+//! rest of the chain sees the original row. Rows it changed or did not pass on
+//! are counted in `REWRITER_STATS`.
 
-use pgrx::pg_sys::{DestReceiver, QueryDesc, TupleDescData, TupleTableSlot};
-use pgrx::PgMemoryContexts;
+use std::cell::Cell;
+use std::collections::BTreeMap;
+use std::ffi::{c_int, CStr};
 
+use pgrx::pg_sys::{self, AsPgCStr, Datum, DestReceiver, Oid, QueryDesc, TupleDescData, TupleTableSlot};
+use pgrx::prelude::*;
+use pgrx::{vardata_any, varsize_any_exhdr, PgMemoryContexts};
+
+use crate::annotation::copy_datum;
 use crate::api::OutputRewriter;
 use crate::hook_ext::EXECUTOR_RUN_HOOK_NESTED_DEPTH;
+use crate::{callback, timeout};
 
 const OUTPUT_REWRITER_DEST: u32 = 2333;
 
+/// The `ExecutorRun` nesting depth of the queries whose rows are rewritten:
+/// the top-level query, or the one `compare()` runs through SPI.
+pub static mut REWRITTEN_DEPTH: usize = 1;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum RewriterMode {
+  Active,
+  Shadow,
+}
+
+impl RewriterMode {
+  pub fn as_str(&self) -> &'static str {
+    match self {
+      RewriterMode::Active => "active",
+      RewriterMode::Shadow => "shadow",
+    }
+  }
+
+  pub fn parse(mode: &str) -> Option<Self> {
+    match mode {
+      "active" => Some(RewriterMode::Active),
+      "shadow" => Some(RewriterMode::Shadow),
+      _ => None,
+    }
+  }
+}
+
+#[derive(Default)]
+pub struct RewriterStats {
+  pub rows: i64,
+  /// Rows a shadow rewriter changed a value of.
+  pub changed: i64,
+  /// Rows a shadow rewriter did not pass on.
+  pub dropped: i64,
+}
+
+/// Rows seen by the rewriters of each plugin in this backend.
+pub static mut REWRITER_STATS: BTreeMap<String, RewriterStats> = BTreeMap::new();
+
 #[repr(C)]
 struct OutputDest {
   pub recv: pgrx::pg_sys::DestReceiver,
-  pub rewriters: Vec<&'static OutputRewriter>,
+  /// (plugin, rewriter, mode)
+  pub rewriters: Vec<(String, OutputRewriter, RewriterMode)>,
   pub rewriter_instances: Vec<*mut std::ffi::c_void>,
   pub original_dest: *mut pgrx::pg_sys::DestReceiver,
+  /// The virtual slot handed to the rewriters, between startup and shutdown.
+  slot: *mut TupleTableSlot,
+  /// The copies handed to shadow rewriters, null for the others.
+  shadow_slots: Vec<*mut TupleTableSlot>,
+  /// Current memory context of the rewriters, reset after every row.
+  row_context: pg_sys::MemoryContext,
 }
 
 struct Context<'a> {
   slot: *mut TupleTableSlot,
   recv: &'a OutputDest,
   depth: usize,
+  /// Memory context to restore before calling the original receiver.
+  caller_context: pg_sys::MemoryContext,
+  /// The shadow rewriter that passes the row on through this context.
+  shadow_of: Option<usize>,
+  /// Whether the row has been passed on.
+  continued: Cell<bool>,
+}
+
+/// Copy all values of `slot` into the virtual slot `copy`.
+pub(crate) unsafe fn copy_slot(slot: *mut TupleTableSlot, copy: *mut TupleTableSlot) {
+  let natts = (*(*slot).tts_tupleDescriptor).natts;
+  if ((*slot).tts_nvalid as c_int) < natts {
+    pg_sys::slot_getsomeattrs_int(slot, natts);
+  }
+  (*(*copy).tts_ops).clear.unwrap()(copy);
+  std::ptr::copy_nonoverlapping((*slot).tts_values, (*copy).tts_values, natts as usize);
+  std::ptr::copy_nonoverlapping((*slot).tts_isnull, (*copy).tts_isnull, natts as usize);
+  pg_sys::ExecStoreVirtualTuple(copy);
+}
+
+unsafe fn datum_eq(attr: &pg_sys::FormData_pg_attribute, a: Datum, b: Datum) -> bool {
+  if attr.attbyval || a == b {
+    a == b
+  } else if attr.attlen == -1 {
+    let a = pg_sys::pg_detoast_datum_packed(a.cast_mut_ptr());
+    let b = pg_sys::pg_detoast_datum_packed(b.cast_mut_ptr());
+    let (len_a, len_b) = (varsize_any_exhdr(a), varsize_any_exhdr(b));
+    len_a == len_b
+      && std::slice::from_raw_parts(vardata_any(a) as *const u8, len_a)
+        == std::slice::from_raw_parts(vardata_any(b) as *const u8, len_b)
+  } else if attr.attlen == -2 {
+    CStr::from_ptr(a.cast_mut_ptr()) == CStr::from_ptr(b.cast_mut_ptr())
+  } else {
+    let len = attr.attlen as usize;
+    std::slice::from_raw_parts(a.cast_mut_ptr::<u8>(), len) == std::slice::from_raw_parts(b.cast_mut_ptr::<u8>(), len)
+  }
+}
+
+/// Whether any value of `a` differs from the same value of `b`.
+pub(crate) unsafe fn slots_differ(a: *mut TupleTableSlot, b: *mut TupleTableSlot) -> bool {
+  let tupdesc = &*(*a).tts_tupleDescriptor;
+  (0..tupdesc.natts as usize).any(|i| {
+    let attr = &*tupdesc.attrs.as_ptr().add(i);
+    let (a_null, b_null) = (*(*a).tts_isnull.add(i), *(*b).tts_isnull.add(i));
+    a_null != b_null || (!a_null && !datum_eq(attr, *(*a).tts_values.add(i), *(*b).tts_values.add(i)))
+  })
 }
 
 impl OutputDest {
-  fn new(rewriters: Vec<&'static OutputRewriter>, original_dest: *mut pgrx::pg_sys::DestReceiver) -> Self {
+  unsafe fn new(
+    rewriters: Vec<(String, OutputRewriter, RewriterMode)>,
+    original_dest: *mut pgrx::pg_sys::DestReceiver,
+  ) -> Self {
     Self {
       recv: pgrx::pg_sys::DestReceiver {
         receiveSlot: Some(Self::receive_slot),
@@ -35,40 +150,99 @@ impl OutputDest {
       rewriters,
       rewriter_instances: vec![],
       original_dest,
+      slot: std::ptr::null_mut(),
+      shadow_slots: vec![],
+      row_context: pg_sys::AllocSetContextCreateExtended(
+        pg_sys::CurrentMemoryContext,
+        "pgext output rewriter row".as_pg_cstr(),
+        pg_sys::ALLOCSET_DEFAULT_MINSIZE as usize,
+        pg_sys::ALLOCSET_DEFAULT_INITSIZE as usize,
+        pg_sys::ALLOCSET_DEFAULT_MAXSIZE as usize,
+      ),
     }
   }
 
   unsafe extern "C" fn receive_slot(slot: *mut TupleTableSlot, recv: *mut DestReceiver) -> bool {
     let recv = &mut *(recv as *mut OutputDest);
-    let mut ctx = Context { slot, recv, depth: 0 };
-    Self::receive_slot_callback((&mut ctx) as *mut _ as *mut std::ffi::c_void)
+    let row_context = recv.row_context;
+    copy_slot(slot, recv.slot);
+    let mut ctx = Context {
+      slot: recv.slot,
+      recv,
+      depth: 0,
+      caller_context: pg_sys::CurrentMemoryContext,
+      shadow_of: None,
+      continued: Cell::new(false),
+    };
+    let result = PgMemoryContexts::For(row_context)
+      .switch_to(|_| Self::receive_slot_callback((&mut ctx) as *mut _ as *mut std::ffi::c_void));
+    pg_sys::MemoryContextReset(row_context);
+    result
   }
 
   unsafe extern "C" fn receive_slot_callback(ctx: *mut std::ffi::c_void) -> bool {
-    let ctx = &*(ctx as *const Context);
+    callback::outside(|| Self::receive_next(&*(ctx as *const Context)))
+  }
+
+  unsafe fn receive_next(ctx: &Context) -> bool {
+    if let Some(shadow_of) = ctx.shadow_of {
+      ctx.continued.set(true);
+      if slots_differ(ctx.recv.shadow_slots[shadow_of], ctx.slot) {
+        REWRITER_STATS
+          .get_mut(&ctx.recv.rewriters[shadow_of].0)
+          .unwrap()
+          .changed += 1;
+      }
+    }
     let mut next_ctx = Context {
       slot: ctx.slot,
       recv: ctx.recv,
       depth: ctx.depth + 1,
+      caller_context: ctx.caller_context,
+      shadow_of: None,
+      continued: Cell::new(false),
     };
     if ctx.depth < ctx.recv.rewriters.len() {
       let rr = ctx.recv.rewriter_instances[ctx.depth];
-      ctx.recv.rewriters[ctx.depth].receive_slot.unwrap()(
-        rr,
-        ctx.slot,
-        (&mut next_ctx) as *mut _ as *mut std::ffi::c_void,
-        Self::receive_slot_callback,
-      )
+      let (plugin, rewriter, mode) = &ctx.recv.rewriters[ctx.depth];
+      if let (Some(receive_slot), false) = (rewriter.receive_slot, timeout::is_skipped(plugin)) {
+        REWRITER_STATS.entry(plugin.clone()).or_default().rows += 1;
+        let slot = if *mode == RewriterMode::Shadow {
+          next_ctx.shadow_of = Some(ctx.depth);
+          copy_slot(ctx.slot, ctx.recv.shadow_slots[ctx.depth]);
+          ctx.recv.shadow_slots[ctx.depth]
+        } else {
+          ctx.slot
+        };
+        let result = callback::run("output_rewriter.receive_slot", plugin, || {
+          receive_slot(
+            rr,
+            slot,
+            (&mut next_ctx) as *mut _ as *mut std::ffi::c_void,
+            Self::receive_slot_callback,
+          )
+        });
+        if next_ctx.shadow_of.is_some() && !next_ctx.continued.get() {
+          // a shadow rewriter cannot drop the row either
+          REWRITER_STATS.get_mut(plugin).unwrap().dropped += 1;
+          next_ctx.shadow_of = None;
+          return Self::receive_slot_callback((&mut next_ctx) as *mut _ as *mut std::ffi::c_void);
+        }
+        result
+      } else {
+        Self::receive_slot_callback((&mut next_ctx) as *mut _ as *mut std::ffi::c_void)
+      }
     } else {
-      (*ctx.recv.original_dest).receiveSlot.unwrap()(ctx.slot, ctx.recv.original_dest)
+      PgMemoryContexts::For(ctx.caller_context)
+        .switch_to(|_| (*ctx.recv.original_dest).receiveSlot.unwrap()(ctx.slot, ctx.recv.original_dest))
     }
   }
 
   unsafe extern "C" fn destroy(recv: *mut DestReceiver) {
     let recv = &mut *(recv as *mut OutputDest);
-    for (r, rr) in recv.rewriters.iter().zip(recv.rewriter_instances.iter()) {
+    for ((plugin, r, _), rr) in recv.rewriters.iter().zip(recv.rewriter_instances.iter()) {
       if let Some(f) = r.destroy {
-        f(*rr);
+        callback::run("output_rewriter.destroy", plugin, || f(*rr));
       }
     }
     (*recv.original_dest).rDestroy.unwrap()(recv.original_dest)
@@ -76,22 +250,38 @@ impl OutputDest {
 
   unsafe extern "C" fn shutdown(recv: *mut DestReceiver) {
     let recv = &mut *(recv as *mut OutputDest);
-    for (r, rr) in recv.rewriters.iter().zip(recv.rewriter_instances.iter()) {
+    for ((plugin, r, _), rr) in recv.rewriters.iter().zip(recv.rewriter_instances.iter()) {
       if let Some(f) = r.shutdown {
-        f(*rr);
+        callback::run("output_rewriter.shutdown", plugin, || f(*rr));
       }
     }
+    pg_sys::ExecDropSingleTupleTableSlot(recv.slot);
+    recv.slot = std::ptr::null_mut();
+    for slot in recv.shadow_slots.drain(..).filter(|slot| !slot.is_null()) {
+      pg_sys::ExecDropSingleTupleTableSlot(slot);
+    }
     (*recv.original_dest).rShutdown.unwrap()(recv.original_dest)
   }
 
   unsafe extern "C" fn startup(recv: *mut DestReceiver, operation: c_int, tuple_type: *mut TupleDescData) {
     let recv = &mut *(recv as *mut OutputDest);
     (*recv.original_dest).rStartup.unwrap()(recv.original_dest, operation, tuple_type);
+    recv.slot = pg_sys::MakeSingleTupleTableSlot(tuple_type, &pg_sys::TTSOpsVirtual);
+    recv.shadow_slots = recv
+      .rewriters
+      .iter()
+      .map(|(_, _, mode)| match mode {
+        RewriterMode::Shadow => pg_sys::MakeSingleTupleTableSlot(tuple_type, &pg_sys::TTSOpsVirtual),
+        RewriterMode::Active => std::ptr::null_mut(),
+      })
+      .collect();
 
     let mut rewriter_instances = vec![];
-    for r in recv.rewriters.iter() {
+    for (plugin, r, _) in recv.rewriters.iter() {
       if let Some(f) = r.startup {
-        rewriter_instances.push(f(operation, tuple_type));
+        rewriter_instances.push(callback::run("output_rewriter.startup", plugin, || {
+          f(operation, tuple_type)
+        }));
       } else {
         rewriter_instances.push(std::ptr::null_mut());
       }
@@ -100,17 +290,116 @@ impl OutputDest {
   }
 }
 
+/// Typed access to the slot handed to output rewriters. Values set through it
+/// are copied into the row's memory context, so callers may free their own.
+pub struct RewriterSlot(*mut TupleTableSlot);
+
+impl RewriterSlot {
+  /// `slot` must be the slot passed to `receive_slot` of an output rewriter.
+  pub unsafe fn from_ptr(slot: *mut TupleTableSlot) -> Self {
+    Self(slot)
+  }
+
+  pub unsafe fn natts(&self) -> usize {
+    (*(*self.0).tts_tupleDescriptor).natts as usize
+  }
+
+  unsafe fn check_index(&self, index: usize) {
+    if index >= self.natts() {
+      error!("output rewriter slot has no attribute {}", index);
+    }
+  }
+
+  unsafe fn attr(&self, index: usize) -> &pg_sys::FormData_pg_attribute {
+    self.check_index(index);
+    &*(*(*self.0).tts_tupleDescriptor).attrs.as_ptr().add(index)
+  }
+
+  /// Index of the (non-dropped) attribute called `name`.
+  pub unsafe fn attr_index(&self, name: &str) -> Option<usize> {
+    (0..self.natts()).find(|&i| {
+      let attr = self.attr(i);
+      !attr.attisdropped && CStr::from_ptr(attr.attname.data.as_ptr()).to_bytes() == name.as_bytes()
+    })
+  }
+
+  pub unsafe fn attr_type(&self, index: usize) -> Oid {
+    self.attr(index).atttypid
+  }
+
+  /// Indexes of all attributes of type `typid`.
+  pub unsafe fn attrs_of_type(&self, typid: Oid) -> Vec<usize> {
+    (0..self.natts()).filter(|&i| self.attr_type(i) == typid).collect()
+  }
+
+  pub unsafe fn get_datum(&self, index: usize) -> (Datum, bool) {
+    self.check_index(index);
+    (*(*self.0).tts_values.add(index), *(*self.0).tts_isnull.add(index))
+  }
+
+  /// Raise an error if attribute `index` is not of type `typid`.
+  pub unsafe fn check_type(&self, index: usize, typid: Oid) {
+    let atttypid = self.attr_type(index);
+    if atttypid != typid {
+      error!(
+        "output rewriter slot attribute {} has type {}, not {}",
+        index,
+        CStr::from_ptr(pg_sys::format_type_be(atttypid)).to_string_lossy(),
+        CStr::from_ptr(pg_sys::format_type_be(typid)).to_string_lossy()
+      );
+    }
+  }
+
+  /// Replace the value of attribute `index`, which must be of type `typid`.
+  pub unsafe fn set_datum(&mut self, index: usize, typid: Oid, value: Datum, isnull: bool) {
+    self.check_type(index, typid);
+    *(*self.0).tts_values.add(index) = if isnull { value } else { copy_datum(value, typid) };
+    *(*self.0).tts_isnull.add(index) = isnull;
+  }
+
+  fn index_of(&self, name: &str) -> usize {
+    unsafe { self.attr_index(name) }.unwrap_or_else(|| error!("output rewriter slot has no attribute \"{}\"", name))
+  }
+
+  /// The value of attribute `name`. Raises an error if it does not exist or
+  /// cannot be read as `T`.
+  pub unsafe fn get<T: FromDatum + IntoDatum>(&self, name: &str) -> Option<T> {
+    let index = self.index_of(name);
+    let (value, isnull) = self.get_datum(index);
+    T::try_from_datum(value, isnull, self.attr_type(index)).unwrap_or_else(|e| error!("{}: {}", name, e))
+  }
+
+  /// Replace the value of attribute `name`. Raises an error if it does not
+  /// exist or cannot hold a `T`.
+  pub unsafe fn set<T: IntoDatum>(&mut self, name: &str, value: Option<T>) {
+    let index = self.index_of(name);
+    let typid = self.attr_type(index);
+    if !T::is_compatible_with(typid) {
+      error!(
+        "output rewriter slot attribute \"{}\" has type {}, not {}",
+        name,
+        CStr::from_ptr(pg_sys::format_type_be(typid)).to_string_lossy(),
+        std::any::type_name::<T>()
+      );
+    }
+    match value.and_then(|v| v.into_datum()) {
+      Some(datum) => self.set_datum(index, typid, datum, false),
+      None => self.set_datum(index, typid, Datum::from(0), true),
+    }
+  }
+}
+
 pub(crate) unsafe extern "C" fn before_executor_run(query_desc: *mut QueryDesc, _: i32, _: u64, _: bool) {
-  if EXECUTOR_RUN_HOOK_NESTED_DEPTH == 1 {
-    let mut rewriters: Vec<&'static OutputRewriter> = vec![];
-    for (_, rewriter, enabled) in &crate::ALL_HOOKS.rewriters {
-      if *enabled {
+  if EXECUTOR_RUN_HOOK_NESTED_DEPTH == REWRITTEN_DEPTH {
+    let mut rewriters: Vec<(String, OutputRewriter, RewriterMode)> = vec![];
+    for (plugin, rewriter, enabled, mode) in &crate::ALL_HOOKS.rewriters {
+      if *enabled && !timeout::is_skipped(plugin) {
         if let Some(filter) = rewriter.filter {
-          if !filter(query_desc) {
+          if !callback::run("output_rewriter.filter", plugin, || filter(query_desc)) {
             continue;
           }
         }
-        rewriters.push(rewriter);
+        rewriters.push((plugin.clone(), rewriter.clone(), *mode));
       }
     }
     if !rewriters.is_empty() {
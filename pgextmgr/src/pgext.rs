@@ -1,6 +1,14 @@
+use std::ffi::c_int;
+
 use pgrx::pg_sys::{uint64, QueryDesc, ScanDirection};
 
-use crate::output_rewriter;
+use crate::{annotation, custom_scan, explain, output_rewriter};
+
+pub(crate) unsafe extern "C" fn before_executor_start(query_desc: *mut QueryDesc, eflags: c_int) {
+  custom_scan::count_chosen(query_desc, eflags)
+}
+
+pub(crate) unsafe extern "C" fn after_executor_start(_query_desc: *mut QueryDesc, _eflags: c_int) {}
 
 pub(crate) unsafe extern "C" fn before_executor_run(
   query_desc: *mut QueryDesc,
@@ -19,3 +27,13 @@ pub(crate) unsafe extern "C" fn after_executor_run(
 ) {
   output_rewriter::after_executor_run(query_desc, direction, count, execute_once)
 }
+
+pub(crate) unsafe extern "C" fn before_executor_end(query_desc: *mut QueryDesc) {
+  explain::explain_sections(query_desc)
+}
+
+pub(crate) unsafe extern "C" fn after_executor_end(_query_desc: *mut QueryDesc) {
+  if annotation::top_level() {
+    annotation::reset();
+  }
+}
@@ -0,0 +1,170 @@
+//! Multiplexing `PLpgSQL_plugin`
+//!
+//! PL/pgSQL only supports a single instrumentation plugin, which it looks up in
+//! the `PLpgSQL_plugin` rendezvous variable. pgextmgr owns that variable and
+//! fans every callback out to all registered plugins in load order. Each plugin
+//! gets its own `estate->plugin_info`, which is swapped in around its calls.
+
+use std::ffi::c_void;
+
+use pgrx::pg_sys::{self, PLpgSQL_execstate, PLpgSQL_function, PLpgSQL_plugin, PLpgSQL_stmt};
+use pgrx::prelude::*;
+
+use crate::callback::enabled;
+
+/// (plugin, its `PLpgSQL_plugin`, or `None` once the plugin is unregistered).
+/// Entries are never removed, so that running functions keep their indexes.
+pub static mut PLPGSQL_PLUGINS: Vec<(String, Option<*mut PLpgSQL_plugin>)> = Vec::new();
+
+static mut MUX_PLUGIN: PLpgSQL_plugin = PLpgSQL_plugin {
+  func_setup: Some(mux_func_setup),
+  func_beg: Some(mux_func_beg),
+  func_end: Some(mux_func_end),
+  stmt_beg: Some(mux_stmt_beg),
+  stmt_end: Some(mux_stmt_end),
+  error_callback: None,
+  assign_expr: None,
+  #[cfg(feature = "pg15")]
+  assign_value: None,
+  #[cfg(feature = "pg15")]
+  eval_datum: None,
+  #[cfg(feature = "pg15")]
+  cast_value: None,
+};
+
+/// Per-call state stored in `estate->plugin_info` of the multiplexer.
+#[repr(C)]
+struct MuxState {
+  len: usize,
+  frames: *mut PluginFrame,
+}
+
+#[repr(C)]
+struct PluginFrame {
+  /// `plugin_info` of the plugin for this call.
+  info: *mut c_void,
+  /// Whether the plugin was enabled when the call started. A plugin that is
+  /// enabled or disabled halfway through a call is only affected by the next
+  /// call, so that it never sees `func_end` without `func_beg`.
+  active: bool,
+}
+
+unsafe fn rendezvous() -> *mut *mut c_void {
+  let name = std::ffi::CString::new("PLpgSQL_plugin").unwrap();
+  pg_sys::find_rendezvous_variable(name.as_ptr())
+}
+
+pub unsafe fn register(plugin: &str, hooks: *mut PLpgSQL_plugin) {
+  PLPGSQL_PLUGINS.push((plugin.to_string(), Some(hooks)));
+}
+
+pub unsafe fn unregister(plugin: &str) {
+  for (name, hooks) in PLPGSQL_PLUGINS.iter_mut() {
+    if name == plugin {
+      *hooks = None;
+    }
+  }
+}
+
+/// Hide the multiplexer from the plugin being loaded, so that a plugin setting
+/// the rendezvous variable itself can be detected.
+pub unsafe fn before_init() {
+  *rendezvous() = std::ptr::null_mut();
+}
+
+/// Take over a plugin installed in the original way and put the multiplexer
+/// back in place.
+pub unsafe fn after_init(plugin: &str) {
+  let var = rendezvous();
+  let hooks = *var as *mut PLpgSQL_plugin;
+  if !hooks.is_null() && !std::ptr::eq(hooks, std::ptr::addr_of!(MUX_PLUGIN)) {
+    register(plugin, hooks);
+  }
+  *var = std::ptr::addr_of_mut!(MUX_PLUGIN) as *mut c_void;
+}
+
+unsafe fn frames<'a>(estate: *mut PLpgSQL_execstate) -> &'a mut [PluginFrame] {
+  let state = (*estate).plugin_info as *mut MuxState;
+  if state.is_null() {
+    return &mut [];
+  }
+  std::slice::from_raw_parts_mut((*state).frames, (*state).len)
+}
+
+/// Call `f` for every plugin active in this call, with the plugin's own
+/// `plugin_info` in place.
+unsafe fn for_each_plugin(estate: *mut PLpgSQL_execstate, f: impl Fn(&PLpgSQL_plugin)) {
+  let state = (*estate).plugin_info;
+  for (frame, (_, hooks)) in frames(estate).iter_mut().zip(PLPGSQL_PLUGINS.iter()) {
+    if let (true, Some(hooks)) = (frame.active, hooks) {
+      (*estate).plugin_info = frame.info;
+      f(&**hooks);
+      frame.info = (*estate).plugin_info;
+    }
+  }
+  (*estate).plugin_info = state;
+}
+
+#[pg_guard]
+unsafe extern "C" fn mux_func_setup(estate: *mut PLpgSQL_execstate, func: *mut PLpgSQL_function) {
+  let len = PLPGSQL_PLUGINS.len();
+  let state = pg_sys::palloc0(std::mem::size_of::<MuxState>()) as *mut MuxState;
+  (*state).len = len;
+  (*state).frames = pg_sys::palloc0(std::mem::size_of::<PluginFrame>() * len.max(1)) as *mut PluginFrame;
+  for (i, (name, hooks)) in PLPGSQL_PLUGINS.iter().enumerate() {
+    if let Some(hooks) = hooks {
+      // PL/pgSQL only fills in the helper functions of the plugin it knows about
+      (**hooks).error_callback = MUX_PLUGIN.error_callback;
+      (**hooks).assign_expr = MUX_PLUGIN.assign_expr;
+      #[cfg(feature = "pg15")]
+      {
+        (**hooks).assign_value = MUX_PLUGIN.assign_value;
+        (**hooks).eval_datum = MUX_PLUGIN.eval_datum;
+        (**hooks).cast_value = MUX_PLUGIN.cast_value;
+      }
+      (*(*state).frames.add(i)).active = enabled(name);
+    }
+  }
+  (*estate).plugin_info = state as *mut c_void;
+  for_each_plugin(estate, |hooks| {
+    if let Some(func_setup) = hooks.func_setup {
+      func_setup(estate, func);
+    }
+  });
+}
+
+#[pg_guard]
+unsafe extern "C" fn mux_func_beg(estate: *mut PLpgSQL_execstate, func: *mut PLpgSQL_function) {
+  for_each_plugin(estate, |hooks| {
+    if let Some(func_beg) = hooks.func_beg {
+      func_beg(estate, func);
+    }
+  });
+}
+
+#[pg_guard]
+unsafe extern "C" fn mux_func_end(estate: *mut PLpgSQL_execstate, func: *mut PLpgSQL_function) {
+  for_each_plugin(estate, |hooks| {
+    if let Some(func_end) = hooks.func_end {
+      func_end(estate, func);
+    }
+  });
+}
+
+#[pg_guard]
+unsafe extern "C" fn mux_stmt_beg(estate: *mut PLpgSQL_execstate, stmt: *mut PLpgSQL_stmt) {
+  for_each_plugin(estate, |hooks| {
+    if let Some(stmt_beg) = hooks.stmt_beg {
+      stmt_beg(estate, stmt);
+    }
+  });
+}
+
+#[pg_guard]
+unsafe extern "C" fn mux_stmt_end(estate: *mut PLpgSQL_execstate, stmt: *mut PLpgSQL_stmt) {
+  for_each_plugin(estate, |hooks| {
+    if let Some(stmt_end) = hooks.stmt_end {
+      stmt_end(estate, stmt);
+    }
+  });
+}
@@ -0,0 +1,202 @@
+//! Canonical query id and normalized text of the current statement
+//!
+//! From PostgreSQL 14 on, pgextmgr turns on the core query jumbling
+//! (`EnableQueryId`) and publishes, before the `post_parse_analyze_hook` of any
+//! plugin runs, the query id together with the query text with its constants
+//! replaced by `$n`, the same text pg_stat_statements stores. Plugins read both
+//! through `PgExtApi` instead of jumbling the query themselves. They are kept
+//! as the `query_id` and `normalized_query` annotations of `__pgext`, so they
+//! describe the statement analyzed last in the current top-level statement.
+//!
+//! PostgreSQL 13 has no core jumbling. There the id is whatever a plugin set in
+//! `Query.queryId`, picked up once all hooks have run, and there is no
+//! normalized text.
+
+use std::ffi::{c_char, CStr};
+
+use pgrx::pg_sys::{self, ParseState, Query};
+
+use crate::annotation;
+
+const PLUGIN: &str = "__pgext";
+
+/// The query id of the statement analyzed last, or 0 if there is none.
+pub unsafe fn current() -> u64 {
+  match annotation::get_annotation(PLUGIN, "query_id", pg_sys::INT8OID) {
+    Some((value, false)) => value.value() as u64,
+    _ => 0,
+  }
+}
+
+/// The normalized text of the statement analyzed last, or null if there is
+/// none. Lives as long as the current query.
+pub unsafe fn normalized_query() -> *const c_char {
+  match annotation::get_annotation(PLUGIN, "normalized_query", pg_sys::CSTRINGOID) {
+    Some((value, false)) => value.cast_mut_ptr(),
+    _ => std::ptr::null(),
+  }
+}
+
+unsafe fn publish(query_id: u64, normalized: Option<&CStr>) {
+  annotation::set_annotation(PLUGIN, "query_id", pg_sys::INT8OID, (query_id as i64).into(), false);
+  let (value, isnull) = match normalized {
+    Some(text) => (text.as_ptr().into(), false),
+    None => (pg_sys::Datum::from(0usize), true),
+  };
+  annotation::set_annotation(PLUGIN, "normalized_query", pg_sys::CSTRINGOID, value, isnull);
+}
+
+/// Length of the constant at the start of `text`, following the token rules
+/// of the SQL scanner closely enough for the literals the jumbling records.
+fn constant_len(text: &[u8]) -> usize {
+  // negative numbers are recorded at the minus sign
+  if text.first() == Some(&b'-') {
+    let spaces = text[1..].iter().take_while(|c| c.is_ascii_whitespace()).count();
+    return 1 + spaces + token_len(&text[1 + spaces..]);
+  }
+  token_len(text)
+}
+
+fn token_len(text: &[u8]) -> usize {
+  let is_ident = |c: &u8| c.is_ascii_alphanumeric() || *c == b'_' || *c >= 0x80;
+  match text {
+    [b'\'', ..] => quoted_len(text, false),
+    [b'e' | b'E', b'\'', ..] => 1 + quoted_len(&text[1..], true),
+    [b'b' | b'B' | b'x' | b'X' | b'n' | b'N', b'\'', ..] => 1 + quoted_len(&text[1..], false),
+    [b'u' | b'U', b'&', b'\'', ..] => 2 + quoted_len(&text[2..], false),
+    [b'$', rest @ ..] => {
+      let tag_len = rest.iter().take_while(|c| is_ident(c) && !c.is_ascii_digit()).count();
+      if rest.get(tag_len) != Some(&b'$') {
+        // a parameter
+        return 1 + rest.iter().take_while(|c| c.is_ascii_digit()).count();
+      }
+      let tag = &text[..tag_len + 2];
+      text[tag.len()..]
+        .windows(tag.len())
+        .position(|window| window == tag)
+        .map_or(text.len(), |end| 2 * tag.len() + end)
+    }
+    [c, ..] if c.is_ascii_digit() || *c == b'.' => {
+      let mut len = text.iter().take_while(|c| c.is_ascii_digit() || **c == b'.').count();
+      if let [b'e' | b'E', rest @ ..] = &text[len..] {
+        let sign = matches!(rest.first(), Some(b'+' | b'-')) as usize;
+        let digits = rest[sign..].iter().take_while(|c| c.is_ascii_digit()).count();
+        if digits > 0 {
+          len += 1 + sign + digits;
+        }
+      }
+      len
+    }
+    _ => text.iter().take_while(|c| is_ident(c)).count().max(1),
+  }
+}
+
+/// Length of the quoted string at the start of `text`, including the quotes.
+fn quoted_len(text: &[u8], escapes: bool) -> usize {
+  let mut i = 1;
+  while i < text.len() {
+    match text[i] {
+      b'\\' if escapes => i += 2,
+      b'\'' if text.get(i + 1) == Some(&b'\'') => i += 2,
+      b'\'' => return i + 1,
+      _ => i += 1,
+    }
+  }
+  text.len()
+}
+
+/// Replace the constants at `locations` (offsets into `text`) with `$n`,
+/// numbered after the `highest_param` parameters of the statement itself.
+/// Duplicate locations are ignored.
+#[cfg_attr(feature = "pg13", allow(dead_code))]
+pub fn normalize(text: &[u8], locations: &[usize], highest_param: usize) -> Vec<u8> {
+  let mut locations = locations.to_vec();
+  locations.sort_unstable();
+  locations.dedup();
+
+  let mut normalized = Vec::with_capacity(text.len());
+  let mut copied = 0;
+  let mut param = highest_param;
+  for location in locations {
+    if location < copied || location >= text.len() {
+      continue;
+    }
+    param += 1;
+    normalized.extend_from_slice(&text[copied..location]);
+    normalized.extend_from_slice(format!("${}", param).as_bytes());
+    copied = location + constant_len(&text[location..]);
+  }
+  normalized.extend_from_slice(&text[copied.min(text.len())..]);
+  normalized
+}
+
+#[cfg(not(feature = "pg13"))]
+pub(crate) unsafe extern "C" fn before_post_parse_analyze(
+  pstate: *mut ParseState,
+  query: *mut Query,
+  jstate: *mut pg_sys::JumbleState,
+) {
+  if annotation::top_level() {
+    annotation::reset();
+  }
+  if (*query).queryId == 0 || (*pstate).p_sourcetext.is_null() {
+    return;
+  }
+  let mut location = (*query).stmt_location;
+  let mut len = (*query).stmt_len;
+  let text = pg_sys::CleanQuerytext((*pstate).p_sourcetext, &mut location, &mut len);
+  let text = std::slice::from_raw_parts(text as *const u8, len as usize);
+  let (locations, highest_param) = if jstate.is_null() {
+    (vec![], 0)
+  } else {
+    let clocations = std::slice::from_raw_parts((*jstate).clocations, (*jstate).clocations_count as usize);
+    let locations = clocations
+      .iter()
+      .filter(|loc| loc.location >= location)
+      .map(|loc| (loc.location - location) as usize)
+      .collect::<Vec<_>>();
+    (locations, (*jstate).highest_extern_param_id as usize)
+  };
+  let normalized = std::ffi::CString::new(normalize(text, &locations, highest_param)).ok();
+  publish((*query).queryId, normalized.as_deref());
+}
+
+#[cfg(not(feature = "pg13"))]
+pub(crate) unsafe extern "C" fn after_post_parse_analyze(
+  _pstate: *mut ParseState,
+  _query: *mut Query,
+  _jstate: *mut pg_sys::JumbleState,
+) {
+}
+
+/// Called once all plugins are done.
+#[cfg(not(feature = "pg13"))]
+pub(crate) unsafe extern "C" fn standard_post_parse_analyze(
+  _pstate: *mut ParseState,
+  _query: *mut Query,
+  _jstate: *mut pg_sys::JumbleState,
+) {
+}
+
+#[cfg(feature = "pg13")]
+pub(crate) unsafe extern "C" fn before_post_parse_analyze(_pstate: *mut ParseState, _query: *mut Query) {
+  if annotation::top_level() {
+    annotation::reset();
+  }
+}
+
+#[cfg(feature = "pg13")]
+pub(crate) unsafe extern "C" fn after_post_parse_analyze(_pstate: *mut ParseState, query: *mut Query) {
+  if (*query).queryId != 0 {
+    publish((*query).queryId, None);
+  }
+}
+
+/// Called once all plugins are done.
+#[cfg(feature = "pg13")]
+pub(crate) unsafe extern "C" fn standard_post_parse_analyze(_pstate: *mut ParseState, _query: *mut Query) {}
+
+pub unsafe fn init() {
+  #[cfg(not(feature = "pg13"))]
+  pg_sys::EnableQueryId();
+}
@@ -0,0 +1,90 @@
+//! Security hooks
+//!
+//! Plugins do not chain these hooks: pgextmgr calls every plugin in load order
+//! and combines the results, so that loading another plugin can never weaken a
+//! check made by an earlier one. They are also called while the plugin is
+//! disabled, as `disable` is open to every role; only `unregister`, which needs
+//! a superuser, removes them. A hook installed before pgextmgr, e.g. by a
+//! library loaded earlier, is called first and combined the same way.
+//!
+//! * `object_access_hook`: every plugin is called, any of them may raise an
+//!   error to reject the access.
+//! * `ExecutorCheckPerms_hook`: the results are AND-ed, the first plugin that
+//!   denies access ends the chain.
+//! * `row_security_policy_hook_*`: the policies of all plugins are
+//!   concatenated.
+
+use std::ffi::{c_int, c_void};
+
+use pgrx::pg_sys::{self, CmdType, List, ObjectAccessType, Oid, Relation};
+use pgrx::prelude::*;
+
+use crate::hook_mgr::{FanOutHookMgr, ALL_HOOKS};
+
+/// How the results of the plugins on a hook are combined, as shown in
+/// `pgextmgr.security()`.
+pub const OBJECT_ACCESS_COMBINE: &str = "all";
+pub const CHECK_PERMS_COMBINE: &str = "and";
+pub const ROW_SECURITY_COMBINE: &str = "concat";
+/// See `auth.rs`.
+pub const VOTE_COMBINE: &str = "vote";
+
+#[pg_guard]
+pub unsafe extern "C" fn pgext_object_access_hook(
+  access: ObjectAccessType,
+  class_id: Oid,
+  object_id: Oid,
+  sub_id: c_int,
+  arg: *mut c_void,
+) {
+  if let Some(prev) = ALL_HOOKS.object_access_hook.prev() {
+    prev(access, class_id, object_id, sub_id, arg);
+  }
+  for (_, hook, _) in ALL_HOOKS.object_access_hook.hooks() {
+    if let Some(hook) = hook {
+      hook(access, class_id, object_id, sub_id, arg);
+    }
+  }
+}
+
+#[pg_guard]
+pub unsafe extern "C" fn pgext_executor_check_perms_hook(range_tables: *mut List, abort: bool) -> bool {
+  if let Some(prev) = ALL_HOOKS.executor_check_perms_hook.prev() {
+    if !prev(range_tables, abort) {
+      return false;
+    }
+  }
+  for (_, hook, _) in ALL_HOOKS.executor_check_perms_hook.hooks() {
+    if let Some(hook) = hook {
+      if !hook(range_tables, abort) {
+        return false;
+      }
+    }
+  }
+  true
+}
+
+unsafe fn concat_policies(
+  hooks: &FanOutHookMgr<pg_sys::row_security_policy_hook_type, ()>,
+  cmdtype: CmdType,
+  relation: Relation,
+) -> *mut List {
+  let mut policies = std::ptr::null_mut();
+  for hook in std::iter::once(hooks.prev())
+    .chain(hooks.hooks().iter().map(|(_, hook, _)| *hook))
+    .flatten()
+  {
+    policies = pg_sys::list_concat(policies, hook(cmdtype, relation));
+  }
+  policies
+}
+
+#[pg_guard]
+pub unsafe extern "C" fn pgext_row_security_policy_hook_permissive(cmdtype: CmdType, relation: Relation) -> *mut List {
+  concat_policies(&ALL_HOOKS.row_security_policy_hook_permissive, cmdtype, relation)
+}
+
+#[pg_guard]
+pub unsafe extern "C" fn pgext_row_security_policy_hook_restrictive(cmdtype: CmdType, relation: Relation) -> *mut List {
+  concat_policies(&ALL_HOOKS.row_security_policy_hook_restrictive, cmdtype, relation)
+}
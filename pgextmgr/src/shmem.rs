@@ -0,0 +1,142 @@
+//! Shared memory requested by plugins through `PgExtApi`
+//!
+//! Plugins request named segments and LWLock tranches while they are being
+//! loaded. pgextmgr reserves all of them from `shmem_request_hook` (or right
+//! away before PostgreSQL 15, which has no such hook), and hands each plugin
+//! its segment from `shmem_startup_hook`. Names are prefixed with the plugin
+//! name, so that plugins cannot collide.
+
+use std::ffi::{c_char, c_int, c_void, CStr, CString};
+
+use pgrx::pg_sys::{self, LWLockPadded};
+use pgrx::prelude::*;
+
+use crate::dependency;
+
+/// Called once the segment is attached. `found` is true if the segment had
+/// already been initialized (e.g. in another process under `EXEC_BACKEND`).
+pub type ShmemStartup = Option<unsafe extern "C" fn(segment: *mut c_void, found: bool)>;
+
+pub struct ShmemSegment {
+  pub plugin: String,
+  pub name: String,
+  pub size: usize,
+  startup: ShmemStartup,
+  pub segment: *mut c_void,
+}
+
+pub static mut SHMEM_SEGMENTS: Vec<ShmemSegment> = Vec::new();
+
+/// (plugin, tranche name, number of locks)
+pub static mut LWLOCK_TRANCHES: Vec<(String, String, c_int)> = Vec::new();
+
+#[cfg(feature = "pg15")]
+static mut PREV_SHMEM_REQUEST_HOOK: pg_sys::shmem_request_hook_type = None;
+static mut PREV_SHMEM_STARTUP_HOOK: pg_sys::shmem_startup_hook_type = None;
+
+/// Position of `AddinShmemInitLock` in `MainLWLockArray`, as generated into
+/// `storage/lwlocknames.h` (the same from PostgreSQL 13 to 15). pgrx does not
+/// bind the `AddinShmemInitLock` macro itself.
+const ADDIN_SHMEM_INIT_LOCK: usize = 21;
+
+/// Requests are only possible while `shared_preload_libraries` are loaded.
+unsafe fn check_requests_allowed() {
+  if !pg_sys::process_shared_preload_libraries_in_progress {
+    error!("shared memory can only be requested from _PG_init of a preloaded plugin");
+  }
+}
+
+unsafe fn full_name(plugin: &str, name: *const c_char) -> String {
+  format!("{}.{}", plugin, CStr::from_ptr(name).to_string_lossy())
+}
+
+unsafe fn reserve_segment(size: usize) {
+  pg_sys::RequestAddinShmemSpace(size);
+}
+
+unsafe fn reserve_tranche(tranche: &str, num_locks: c_int) {
+  let tranche = CString::new(tranche).unwrap();
+  pg_sys::RequestNamedLWLockTranche(tranche.as_ptr(), num_locks);
+}
+
+pub unsafe fn request_shmem(plugin: &str, name: *const c_char, size: usize, startup: ShmemStartup) {
+  check_requests_allowed();
+  #[cfg(not(feature = "pg15"))]
+  reserve_segment(size);
+  SHMEM_SEGMENTS.push(ShmemSegment {
+    plugin: plugin.to_string(),
+    name: full_name(plugin, name),
+    size,
+    startup,
+    segment: std::ptr::null_mut(),
+  });
+}
+
+pub unsafe fn request_lwlocks(plugin: &str, tranche: *const c_char, num_locks: c_int) {
+  check_requests_allowed();
+  let tranche = full_name(plugin, tranche);
+  #[cfg(not(feature = "pg15"))]
+  reserve_tranche(&tranche, num_locks);
+  LWLOCK_TRANCHES.push((plugin.to_string(), tranche, num_locks));
+}
+
+pub unsafe fn get_lwlocks(plugin: &str, tranche: *const c_char) -> *mut LWLockPadded {
+  let name = CString::new(full_name(plugin, tranche)).unwrap();
+  pg_sys::GetNamedLWLockTranche(name.as_ptr())
+}
+
+/// Stop listing the segments and tranches of an unregistered plugin. They stay
+/// allocated, as shared memory cannot be given back.
+pub unsafe fn unregister(plugin: &str) {
+  SHMEM_SEGMENTS.retain(|segment| segment.plugin != plugin);
+  LWLOCK_TRANCHES.retain(|(name, _, _)| name != plugin);
+}
+
+/// Called once all shared_preload_libraries have been loaded, which is also the
+/// earliest point where all dependency declarations are known.
+#[cfg(feature = "pg15")]
+#[pg_guard]
+unsafe extern "C" fn pgext_shmem_request_hook() {
+  if let Some(prev) = PREV_SHMEM_REQUEST_HOOK {
+    prev();
+  }
+  dependency::check_dependencies();
+  for segment in SHMEM_SEGMENTS.iter() {
+    reserve_segment(segment.size);
+  }
+  for (_, tranche, num_locks) in LWLOCK_TRANCHES.iter() {
+    reserve_tranche(tranche, *num_locks);
+  }
+}
+
+#[pg_guard]
+unsafe extern "C" fn pgext_shmem_startup_hook() {
+  if let Some(prev) = PREV_SHMEM_STARTUP_HOOK {
+    prev();
+  }
+  #[cfg(not(feature = "pg15"))]
+  if !pg_sys::IsUnderPostmaster {
+    dependency::check_dependencies();
+  }
+  let addin_shmem_init_lock = &mut (*pg_sys::MainLWLockArray.add(ADDIN_SHMEM_INIT_LOCK)).lock;
+  pg_sys::LWLockAcquire(addin_shmem_init_lock, pg_sys::LWLockMode_LW_EXCLUSIVE);
+  for segment in SHMEM_SEGMENTS.iter_mut() {
+    let name = CString::new(segment.name.as_str()).unwrap();
+    let mut found = false;
+    segment.segment = pg_sys::ShmemInitStruct(name.as_ptr(), segment.size, &mut found);
+    if let Some(startup) = segment.startup {
+      startup(segment.segment, found);
+    }
+  }
+  pg_sys::LWLockRelease(addin_shmem_init_lock);
+}
+
+pub unsafe fn init() {
+  #[cfg(feature = "pg15")]
+  {
+    PREV_SHMEM_REQUEST_HOOK = pg_sys::shmem_request_hook;
+    pg_sys::shmem_request_hook = Some(pgext_shmem_request_hook);
+  }
+  PREV_SHMEM_STARTUP_HOOK = pg_sys::shmem_startup_hook;
+  pg_sys::shmem_startup_hook = Some(pgext_shmem_startup_hook);
+}
@@ -0,0 +1,216 @@
+//! Per-plugin time budget of hook callbacks
+//!
+//! Every plugin callback is timed, excluding the time spent in the rest of the
+//! hook chain and in callbacks of other plugins it triggers. A callback that
+//! takes longer than `pgextmgr.hook_timeout.<plugin>` (or
+//! `pgextmgr.hook_timeout` if that is -1) gets a WARNING. With
+//! `pgextmgr.hook_timeout_skip` on, the plugin is then skipped for the rest of
+//! the session, as if it had been disabled in this backend only. PostgreSQL
+//! cannot interrupt a callback safely, so the budget is only checked once the
+//! callback has returned.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::ffi::{c_char, c_int, CString};
+use std::time::{Duration, Instant};
+
+use pgrx::pg_sys;
+use pgrx::prelude::*;
+
+#[derive(Default)]
+pub struct TimeStats {
+  pub calls: i64,
+  pub total: Duration,
+  pub max: Duration,
+  /// Callbacks that exceeded the budget.
+  pub timeouts: i64,
+}
+
+/// Timing of each plugin's callbacks in this backend.
+pub static mut TIME_STATS: BTreeMap<String, TimeStats> = BTreeMap::new();
+
+/// Plugins skipped for the rest of the session.
+pub static mut SKIPPED: BTreeSet<String> = BTreeSet::new();
+
+static mut HOOK_TIMEOUT: c_int = 0;
+static mut HOOK_TIMEOUT_SKIP: bool = false;
+
+/// `pgextmgr.hook_timeout.<plugin>` of every plugin, boxed so that the values
+/// stay in place.
+static mut PLUGIN_TIMEOUTS: BTreeMap<String, Box<c_int>> = BTreeMap::new();
+
+/// A callback being run, or a part of one that does not count against it.
+pub(crate) struct Frame {
+  plugin: Option<String>,
+  start: Instant,
+  /// Time spent in nested frames.
+  excluded: Duration,
+}
+
+pub(crate) static mut FRAMES: Vec<Frame> = Vec::new();
+
+/// The open subtransactions, and how many frames there were when each started.
+static mut SUBXACT_DEPTHS: Vec<(pg_sys::SubTransactionId, usize)> = Vec::new();
+
+pub fn is_skipped(plugin: &str) -> bool {
+  unsafe { SKIPPED.contains(plugin) }
+}
+
+/// The budget of `plugin`, if any.
+unsafe fn budget(plugin: &str) -> Option<Duration> {
+  if plugin == "__pgext" {
+    return None;
+  }
+  let timeout = match PLUGIN_TIMEOUTS.get(plugin) {
+    Some(timeout) if **timeout >= 0 => **timeout,
+    _ => HOOK_TIMEOUT,
+  };
+  (timeout > 0).then(|| Duration::from_millis(timeout as u64))
+}
+
+/// Run `f` as a frame, and return how long it took excluding nested frames.
+unsafe fn timed<R>(plugin: Option<&str>, f: impl FnOnce() -> R) -> (R, Duration) {
+  let depth = FRAMES.len();
+  FRAMES.push(Frame {
+    plugin: plugin.map(str::to_string),
+    start: Instant::now(),
+    excluded: Duration::ZERO,
+  });
+  let result = f();
+  if FRAMES.len() <= depth {
+    // the stack was reset by a transaction abort within `f`
+    return (result, Duration::ZERO);
+  }
+  // frames left behind by errors caught within `f` are dropped as well
+  let frame = FRAMES.drain(depth..).next().unwrap();
+  let elapsed = frame.start.elapsed();
+  if let Some(parent) = FRAMES.last_mut() {
+    parent.excluded += elapsed;
+  }
+  (result, elapsed.saturating_sub(frame.excluded))
+}
+
+/// Run a callback of `plugin` and check it against the plugin's budget.
+pub unsafe fn run<R>(plugin: &str, f: impl FnOnce() -> R) -> R {
+  let (result, elapsed) = timed(Some(plugin), f);
+  let stats = TIME_STATS.entry(plugin.to_string()).or_default();
+  stats.calls += 1;
+  stats.total += elapsed;
+  stats.max = stats.max.max(elapsed);
+  if let Some(budget) = budget(plugin) {
+    if elapsed > budget {
+      stats.timeouts += 1;
+      let skip = HOOK_TIMEOUT_SKIP && SKIPPED.insert(plugin.to_string());
+      warning!(
+        "plugin {} took {:.3} ms in a hook, more than its budget of {} ms{}",
+        plugin,
+        elapsed.as_secs_f64() * 1000.0,
+        budget.as_millis(),
+        if skip {
+          ", skipping it for the rest of the session"
+        } else {
+          ""
+        }
+      );
+    }
+  }
+  result
+}
+
+/// Run `f` without counting it against the current callback.
+pub unsafe fn outside<R>(f: impl FnOnce() -> R) -> R {
+  match FRAMES.last() {
+    Some(Frame { plugin: Some(_), .. }) => timed(None, f).0,
+    _ => f(),
+  }
+}
+
+/// Frames of callbacks interrupted by an error are never popped.
+pub unsafe fn reset() {
+  FRAMES.clear();
+  SUBXACT_DEPTHS.clear();
+}
+
+pub unsafe fn start_sub(subid: pg_sys::SubTransactionId) {
+  SUBXACT_DEPTHS.push((subid, FRAMES.len()));
+}
+
+/// An error caught by rolling back to a subtransaction interrupted the
+/// callbacks started within it.
+pub unsafe fn end_sub(subid: pg_sys::SubTransactionId, abort: bool) {
+  if let Some(i) = SUBXACT_DEPTHS.iter().rposition(|(id, _)| *id == subid) {
+    let (_, depth) = SUBXACT_DEPTHS[i];
+    SUBXACT_DEPTHS.truncate(i);
+    if abort {
+      FRAMES.truncate(depth);
+    }
+  }
+}
+
+fn cstr(s: &'static [u8]) -> *const c_char {
+  s.as_ptr() as *const c_char
+}
+
+/// Forget the timing of an unregistered plugin. Its
+/// `pgextmgr.hook_timeout.<plugin>` setting cannot be removed, so the value
+/// the setting points to is leaked.
+pub unsafe fn unregister(plugin: &str) {
+  TIME_STATS.remove(plugin);
+  SKIPPED.remove(plugin);
+  if let Some(timeout) = PLUGIN_TIMEOUTS.remove(plugin) {
+    Box::leak(timeout);
+  }
+}
+
+/// Define `pgextmgr.hook_timeout.<plugin>` once the plugin has been loaded.
+pub unsafe fn after_init(plugin: &str) {
+  if plugin == "__pgext" || PLUGIN_TIMEOUTS.contains_key(plugin) {
+    return;
+  }
+  let value = PLUGIN_TIMEOUTS.entry(plugin.to_string()).or_insert(Box::new(-1));
+  let name = CString::new(format!("pgextmgr.hook_timeout.{}", plugin)).unwrap();
+  // the descriptions are not copied
+  let short_desc = CString::new(format!("Time budget of a hook callback of {} in milliseconds.", plugin)).unwrap();
+  pg_sys::DefineCustomIntVariable(
+    name.as_ptr(),
+    short_desc.into_raw(),
+    cstr(b"-1 uses pgextmgr.hook_timeout.\0"),
+    &mut **value,
+    -1,
+    -1,
+    c_int::MAX,
+    pg_sys::GucContext_PGC_SUSET,
+    pg_sys::GUC_UNIT_MS as c_int,
+    None,
+    None,
+    None,
+  );
+}
+
+pub unsafe fn init() {
+  pg_sys::DefineCustomIntVariable(
+    cstr(b"pgextmgr.hook_timeout\0"),
+    cstr(b"Time budget of a plugin hook callback in milliseconds.\0"),
+    cstr(b"0 turns the budget off.\0"),
+    std::ptr::addr_of_mut!(HOOK_TIMEOUT),
+    0,
+    0,
+    c_int::MAX,
+    pg_sys::GucContext_PGC_SUSET,
+    pg_sys::GUC_UNIT_MS as c_int,
+    None,
+    None,
+    None,
+  );
+  pg_sys::DefineCustomBoolVariable(
+    cstr(b"pgextmgr.hook_timeout_skip\0"),
+    cstr(b"Skip a plugin for the rest of the session once it exceeds its time budget.\0"),
+    std::ptr::null(),
+    std::ptr::addr_of_mut!(HOOK_TIMEOUT_SKIP),
+    false,
+    pg_sys::GucContext_PGC_SUSET,
+    0,
+    None,
+    None,
+    None,
+  );
+}
@@ -0,0 +1,225 @@
+//! Trace of plugin callbacks
+//!
+//! With `pgextmgr.trace` on, every call into a plugin is recorded in a
+//! per-backend ring buffer of `pgextmgr.trace_buffer_size` entries, shown by
+//! `trace()`: the hook, the plugin, how deeply it was nested in other plugin
+//! callbacks, and whether it returned. Callbacks interrupted by an error stay
+//! `running` until the transaction or subtransaction aborts, and are then
+//! marked `error`.
+//! `pgextmgr.trace_statement` limits the trace to top-level statements whose
+//! text matches it as a LIKE pattern.
+
+use std::collections::VecDeque;
+use std::ffi::{c_char, c_int, CStr};
+use std::time::{Duration, Instant};
+
+use pgrx::pg_sys;
+
+use crate::{query_id, INSTALLED_PLUGINS};
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Outcome {
+  Running,
+  Returned,
+  Error,
+  /// The plugin is disabled in this backend.
+  Skipped,
+}
+
+impl Outcome {
+  pub fn as_str(&self) -> &'static str {
+    match self {
+      Outcome::Running => "running",
+      Outcome::Returned => "ok",
+      Outcome::Error => "error",
+      Outcome::Skipped => "skipped",
+    }
+  }
+}
+
+pub struct TraceEntry {
+  pub seq: i64,
+  pub time: pg_sys::TimestampTz,
+  pub hook: &'static str,
+  /// Position of the plugin in load order.
+  pub plugin_id: i64,
+  pub plugin: String,
+  pub depth: i32,
+  pub query_id: u64,
+  pub outcome: Outcome,
+  pub duration: Option<Duration>,
+  start: Instant,
+}
+
+/// The most recent entries, oldest first.
+pub static mut TRACE: VecDeque<TraceEntry> = VecDeque::new();
+
+static mut NEXT_SEQ: i64 = 1;
+/// Callbacks being traced that have not returned yet.
+static mut DEPTH: i32 = 0;
+
+/// The open subtransactions, with the depth and the next sequence number when
+/// each started.
+static mut SUBXACTS: Vec<(pg_sys::SubTransactionId, i32, i64)> = Vec::new();
+
+static mut TRACE_ENABLED: bool = false;
+static mut TRACE_BUFFER_SIZE: c_int = 1000;
+static mut TRACE_STATEMENT: *mut c_char = std::ptr::null_mut();
+
+/// Whether `text` matches the LIKE `pattern`, with `\` as the escape.
+pub fn like(pattern: &[u8], text: &[u8]) -> bool {
+  match pattern {
+    [] => text.is_empty(),
+    [b'%', rest @ ..] => (0..=text.len()).any(|skip| like(rest, &text[skip..])),
+    [b'_', rest @ ..] => !text.is_empty() && like(rest, &text[1..]),
+    [b'\\', c, rest @ ..] | [c, rest @ ..] => text.first() == Some(c) && like(rest, &text[1..]),
+  }
+}
+
+unsafe fn tracing() -> bool {
+  if !TRACE_ENABLED {
+    return false;
+  }
+  if TRACE_STATEMENT.is_null() || *TRACE_STATEMENT == 0 {
+    return true;
+  }
+  !pg_sys::debug_query_string.is_null()
+    && like(
+      CStr::from_ptr(TRACE_STATEMENT).to_bytes(),
+      CStr::from_ptr(pg_sys::debug_query_string).to_bytes(),
+    )
+}
+
+unsafe fn record(hook: &'static str, plugin: &str, outcome: Outcome) -> i64 {
+  while TRACE.len() >= TRACE_BUFFER_SIZE.max(1) as usize {
+    TRACE.pop_front();
+  }
+  let seq = NEXT_SEQ;
+  NEXT_SEQ += 1;
+  TRACE.push_back(TraceEntry {
+    seq,
+    time: pg_sys::GetCurrentTimestamp(),
+    hook,
+    plugin_id: INSTALLED_PLUGINS
+      .iter()
+      .position(|p| p == plugin)
+      .map_or(-1, |id| id as i64),
+    plugin: plugin.to_string(),
+    depth: DEPTH,
+    query_id: query_id::current(),
+    outcome,
+    duration: None,
+    start: Instant::now(),
+  });
+  seq
+}
+
+/// Run a callback of `plugin` from `hook`, recording it if tracing is on.
+pub unsafe fn run<R>(hook: &'static str, plugin: &str, f: impl FnOnce() -> R) -> R {
+  if !tracing() {
+    return f();
+  }
+  let seq = record(hook, plugin, Outcome::Running);
+  DEPTH += 1;
+  let result = f();
+  DEPTH = (DEPTH - 1).max(0);
+  let first = TRACE.front().map_or(seq, |entry| entry.seq);
+  if let Some(entry) = TRACE.get_mut((seq - first) as usize).filter(|entry| entry.seq == seq) {
+    entry.outcome = Outcome::Returned;
+    entry.duration = Some(entry.start.elapsed());
+  }
+  result
+}
+
+/// Record that `hook` passed over the disabled `plugin`.
+pub unsafe fn skipped(hook: &'static str, plugin: &str) {
+  if tracing() {
+    record(hook, plugin, Outcome::Skipped);
+  }
+}
+
+/// Mark the callbacks from `seq` on that are still running as interrupted.
+unsafe fn interrupted(seq: i64) {
+  for entry in TRACE
+    .iter_mut()
+    .filter(|entry| entry.seq >= seq && entry.outcome == Outcome::Running)
+  {
+    entry.outcome = Outcome::Error;
+  }
+}
+
+/// Callbacks interrupted by an error never return.
+pub unsafe fn reset() {
+  DEPTH = 0;
+  SUBXACTS.clear();
+  interrupted(0);
+}
+
+pub unsafe fn start_sub(subid: pg_sys::SubTransactionId) {
+  SUBXACTS.push((subid, DEPTH, NEXT_SEQ));
+}
+
+/// An error caught by rolling back to a subtransaction interrupted the
+/// callbacks started within it.
+pub unsafe fn end_sub(subid: pg_sys::SubTransactionId, abort: bool) {
+  if let Some(i) = SUBXACTS.iter().rposition(|(id, _, _)| *id == subid) {
+    let (_, depth, seq) = SUBXACTS[i];
+    SUBXACTS.truncate(i);
+    if abort {
+      DEPTH = depth;
+      interrupted(seq);
+    }
+  }
+}
+
+/// Drop all entries, and return how many there were.
+pub unsafe fn clear() -> usize {
+  let len = TRACE.len();
+  TRACE.clear();
+  len
+}
+
+fn cstr(s: &'static [u8]) -> *const c_char {
+  s.as_ptr() as *const c_char
+}
+
+pub unsafe fn init() {
+  pg_sys::DefineCustomBoolVariable(
+    cstr(b"pgextmgr.trace\0"),
+    cstr(b"Record every plugin callback, see pgextmgr.trace().\0"),
+    std::ptr::null(),
+    std::ptr::addr_of_mut!(TRACE_ENABLED),
+    false,
+    pg_sys::GucContext_PGC_USERSET,
+    0,
+    None,
+    None,
+    None,
+  );
+  pg_sys::DefineCustomIntVariable(
+    cstr(b"pgextmgr.trace_buffer_size\0"),
+    cstr(b"Number of plugin callbacks kept by pgextmgr.trace.\0"),
+    std::ptr::null(),
+    std::ptr::addr_of_mut!(TRACE_BUFFER_SIZE),
+    1000,
+    1,
+    c_int::MAX,
+    pg_sys::GucContext_PGC_USERSET,
+    0,
+    None,
+    None,
+    None,
+  );
+  pg_sys::DefineCustomStringVariable(
+    cstr(b"pgextmgr.trace_statement\0"),
+    cstr(b"Only trace statements matching this LIKE pattern.\0"),
+    cstr(b"An empty pattern traces all statements.\0"),
+    std::ptr::addr_of_mut!(TRACE_STATEMENT),
+    cstr(b"\0"),
+    pg_sys::GucContext_PGC_USERSET,
+    0,
+    None,
+    None,
+    None,
+  );
+}
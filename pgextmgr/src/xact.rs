@@ -0,0 +1,69 @@
+//! Transaction and subtransaction callbacks of plugins
+//!
+//! pgextmgr registers a single callback of each kind with PostgreSQL and calls
+//! the callbacks of enabled plugins in load order.
+
+use std::ffi::c_void;
+
+use pgrx::pg_sys::{self, SubTransactionId, SubXactEvent, XactEvent};
+use pgrx::prelude::*;
+
+use crate::callback::enabled;
+use crate::hook_mgr::ALL_HOOKS;
+use crate::{timeout, trace};
+
+/// The `arg` a callback was registered with.
+pub struct CallbackArg(pub *mut c_void);
+
+impl Default for CallbackArg {
+  fn default() -> Self {
+    Self(std::ptr::null_mut())
+  }
+}
+
+#[pg_guard]
+unsafe extern "C" fn pgext_xact_callback(event: XactEvent, _arg: *mut c_void) {
+  if event == pg_sys::XactEvent_XACT_EVENT_ABORT {
+    timeout::reset();
+    trace::reset();
+  }
+  for (plugin, callback, arg) in ALL_HOOKS.xact_callbacks.hooks() {
+    if let (true, Some(callback)) = (enabled(plugin), callback) {
+      callback(event, arg.0);
+    }
+  }
+}
+
+#[pg_guard]
+unsafe extern "C" fn pgext_subxact_callback(
+  event: SubXactEvent,
+  my_subid: SubTransactionId,
+  parent_subid: SubTransactionId,
+  _arg: *mut c_void,
+) {
+  match event {
+    pg_sys::SubXactEvent_SUBXACT_EVENT_START_SUB => {
+      timeout::start_sub(my_subid);
+      trace::start_sub(my_subid);
+    }
+    pg_sys::SubXactEvent_SUBXACT_EVENT_COMMIT_SUB => {
+      timeout::end_sub(my_subid, false);
+      trace::end_sub(my_subid, false);
+    }
+    pg_sys::SubXactEvent_SUBXACT_EVENT_ABORT_SUB => {
+      timeout::end_sub(my_subid, true);
+      trace::end_sub(my_subid, true);
+    }
+    _ => {}
+  }
+  for (plugin, callback, arg) in ALL_HOOKS.subxact_callbacks.hooks() {
+    if let (true, Some(callback)) = (enabled(plugin), callback) {
+      callback(event, my_subid, parent_subid, arg.0);
+    }
+  }
+}
+
+pub unsafe fn init() {
+  pg_sys::RegisterXactCallback(Some(pgext_xact_callback), std::ptr::null_mut());
+  pg_sys::RegisterSubXactCallback(Some(pgext_subxact_callback), std::ptr::null_mut());
+}
@@ -3,6 +3,17 @@ use pgrx::prelude::*;
 
 pgrx::pg_module_magic!();
 
+/// `pg_sys` together with the hooks that are not included in the pgrx bindings.
+mod hooks {
+  pub use pgrx::pg_sys::*;
+
+  pub type ClientAuthenticationHookType = Option<unsafe extern "C" fn(port: *mut Port, status: std::ffi::c_int)>;
+
+  extern "C" {
+    pub static mut ClientAuthentication_hook: ClientAuthenticationHookType;
+  }
+}
+
 fn render_addr<T>(func: Option<*const T>) -> Option<String> {
   func.and_then(|f| {
     if f.is_null() {
@@ -26,7 +37,7 @@ fn all() -> TableIterator<'static, (name!(name, String), name!(addr, Option<Stri
         $(
           hooks.push((
             stringify!($hook).to_string(),
-            render_addr(unsafe { pg_sys::$hook }.map(|x| x as *const ())),
+            render_addr(unsafe { hooks::$hook }.map(|x| x as *const ())),
           ));
         )*
     };
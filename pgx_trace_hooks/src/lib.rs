@@ -61,6 +61,7 @@ extern "C" fn executor_end_hook(query_desc: *mut pg_sys::QueryDesc) {
   }
 }
 
+#[cfg(not(feature = "pg13"))]
 #[allow(clippy::too_many_arguments)]
 #[pg_guard]
 extern "C" fn process_utility_hook(
@@ -101,6 +102,28 @@ extern "C" fn process_utility_hook(
   }
 }
 
+/// PostgreSQL 13 has no `readOnlyTree`.
+#[cfg(feature = "pg13")]
+#[pg_guard]
+extern "C" fn process_utility_hook(
+  pstmt: *mut pg_sys::PlannedStmt,
+  query_string: *const std::os::raw::c_char,
+  context: pg_sys::ProcessUtilityContext,
+  params: pg_sys::ParamListInfo,
+  query_env: *mut pg_sys::QueryEnvironment,
+  dest: *mut pg_sys::DestReceiver,
+  qc: *mut pg_sys::QueryCompletion,
+) {
+  info!("ProcessUtility");
+  unsafe {
+    if let Some(prev_hook) = PREV_PROCESS_UTILITY_HOOK {
+      prev_hook(pstmt, query_string, context, params, query_env, dest, qc);
+    } else {
+      pg_sys::standard_ProcessUtility(pstmt, query_string, context, params, query_env, dest, qc);
+    }
+  }
+}
+
 #[pg_guard]
 pub extern "C" fn _PG_init() {
   unsafe {